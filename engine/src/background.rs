@@ -0,0 +1,202 @@
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+use crate::utils::{rgb2srgb, RGBColor, DEFAULT_BG};
+
+const BACKGROUND_COLOR: u32 = 0;
+const BACKGROUND_GRADIENT: u32 = 1;
+const BACKGROUND_ENVIRONMENT: u32 = 2;
+
+/// What fills the pixels no geometry covers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    Color(RGBColor),
+    /// Vertical gradient over the viewport
+    Gradient {
+        top: RGBColor,
+        bottom: RGBColor,
+    },
+    /// The environment cubemap, `blur` goes from 0 (sharp) to 1 (fully
+    /// prefiltered)
+    Environment {
+        blur: f32,
+    },
+    /// Cleared to zero alpha, for compositing over a web page
+    Transparent,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Self::Color(DEFAULT_BG)
+    }
+}
+
+impl Background {
+    pub fn clear_color(&self) -> wgpu::Color {
+        match self {
+            Self::Color(color) => rgb2srgb(color),
+            Self::Transparent => wgpu::Color::TRANSPARENT,
+            _ => wgpu::Color::BLACK,
+        }
+    }
+
+    /// Solid and transparent backgrounds are just the clear color.
+    pub fn needs_skybox(&self) -> bool {
+        matches!(self, Self::Gradient { .. } | Self::Environment { .. })
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        matches!(self, Self::Transparent)
+    }
+
+    fn to_raw(self) -> BackgroundUniform {
+        let rgba = |color: &RGBColor| {
+            let color = rgb2srgb(color);
+            [
+                color.r as f32,
+                color.g as f32,
+                color.b as f32,
+                color.a as f32,
+            ]
+        };
+        let mut raw = BackgroundUniform {
+            top: [0.0; 4],
+            bottom: [0.0; 4],
+            mode: BACKGROUND_COLOR,
+            blur: 0.0,
+            _padding: [0; 2],
+        };
+        match self {
+            Self::Gradient { top, bottom } => {
+                raw.mode = BACKGROUND_GRADIENT;
+                raw.top = rgba(&top);
+                raw.bottom = rgba(&bottom);
+            }
+            Self::Environment { blur } => {
+                raw.mode = BACKGROUND_ENVIRONMENT;
+                raw.blur = blur.clamp(0.0, 1.0);
+            }
+            _ => {}
+        }
+        raw
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BackgroundUniform {
+    top: [f32; 4],
+    bottom: [f32; 4],
+    mode: u32,
+    blur: f32,
+    _padding: [u32; 2],
+}
+
+/// Fullscreen pass for gradient and environment backgrounds, drawn after the
+/// opaque meshes at the far plane.
+pub struct Skybox {
+    pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Skybox {
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        camera_layout: &wgpu::BindGroupLayout,
+        environment_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Background Buffer"),
+            contents: bytemuck::cast_slice(&[Background::default().to_raw()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("background_bind_group_layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("background_bind_group"),
+        });
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Skybox Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/skybox.wgsl").into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skybox Pipeline Layout"),
+            bind_group_layouts: &[camera_layout, environment_layout, &layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &module,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: format.add_srgb_suffix(),
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            pipeline,
+            uniform_buffer,
+            bind_group,
+        }
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, background: &Background) {
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[background.to_raw()]),
+        );
+    }
+
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        environment_bind_group: &'a wgpu::BindGroup,
+    ) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_bind_group(1, environment_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
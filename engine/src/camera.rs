@@ -12,8 +12,8 @@ use crate::utils::Instance;
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
 
 const SAFE_PI: f32 = PI - 0.0001;
@@ -158,6 +158,8 @@ pub struct CameraUniform {
     view_position: [f32; 4],
     // convert the Matrix4 into a 4x4 f32 array
     pub view_proj: [[f32; 4]; 4],
+    // used to turn screen positions back into view rays
+    pub inv_view_proj: [[f32; 4]; 4],
 }
 
 impl CameraUniform {
@@ -166,12 +168,19 @@ impl CameraUniform {
         Self {
             view_position: [0.0; 4],
             view_proj: cgmath::Matrix4::identity().into(),
+            inv_view_proj: cgmath::Matrix4::identity().into(),
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera) {
+        use cgmath::SquareMatrix;
         self.view_position = camera.eye.to_homogeneous().into();
-        self.view_proj = camera.build_view_projection_matrix().into();
+        let view_proj = camera.build_view_projection_matrix();
+        self.view_proj = view_proj.into();
+        self.inv_view_proj = view_proj
+            .invert()
+            .unwrap_or(cgmath::Matrix4::identity())
+            .into();
     }
 }
 
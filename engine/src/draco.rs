@@ -0,0 +1,181 @@
+//! Draco compressed primitives (KHR_draco_mesh_compression). The compressed
+//! bufferView is decoded into the same vertex and index data the accessor
+//! readers produce for uncompressed primitives.
+
+use anyhow::*;
+use draco_oxide_core::attribute::{Attribute, AttributeId, ComponentDataType};
+use draco_oxide_core::types::PointIdx;
+
+use crate::resources::{dequantize, PrimitiveData};
+use crate::utils::Vertex;
+
+pub const EXTENSION: &str = "KHR_draco_mesh_compression";
+
+/// Decodes `primitive` if it is Draco compressed, `None` otherwise.
+pub fn decode_primitive(
+    primitive: &gltf::Primitive,
+    document: &gltf::Document,
+    buffer_data: &[Vec<u8>],
+) -> Result<Option<PrimitiveData>> {
+    let Some(extension) = primitive.extension_value(EXTENSION) else {
+        return Ok(None);
+    };
+    let view_index = extension["bufferView"]
+        .as_u64()
+        .context("Draco extension without a bufferView")?;
+    let view = document
+        .views()
+        .nth(view_index as usize)
+        .with_context(|| format!("Draco bufferView {view_index} doesn't exist"))?;
+    let bytes = buffer_data
+        .get(view.buffer().index())
+        .and_then(|buffer| buffer.get(view.offset()..view.offset() + view.length()))
+        .with_context(|| format!("Draco bufferView {view_index} is out of bounds"))?;
+
+    let mesh = draco_oxide_decoder::decode_mesh(bytes).map_err(|e| anyhow!("{e}"))?;
+
+    // The extension maps each glTF semantic to a Draco attribute id, the
+    // primitive's accessor still describes the decoded data
+    let attribute = |semantic: &str| -> Result<Option<(&Attribute, bool)>> {
+        let Some(id) = extension["attributes"][semantic].as_u64() else {
+            return Ok(None);
+        };
+        let attribute = mesh
+            .attributes
+            .iter()
+            .find(|attribute| attribute.get_id() == AttributeId::new(id as usize))
+            .with_context(|| format!("{semantic} is not in the Draco stream"))?;
+        let normalized = primitive
+            .attributes()
+            .find(|(name, _)| name.to_string() == semantic)
+            .is_some_and(|(_, accessor)| accessor.normalized());
+        Ok(Some((attribute, normalized)))
+    };
+
+    let (positions, _) = attribute("POSITION")?.context("Draco primitive without positions")?;
+    let points = positions.len();
+    let mut vertices = read_attribute::<3>(positions, false, points)?
+        .into_iter()
+        .map(|position| Vertex {
+            position,
+            color: [1.0; 4],
+            tex_coords: Default::default(),
+            normal: Default::default(),
+            tangent: Default::default(),
+            tex_coords_1: Default::default(),
+        })
+        .collect::<Vec<_>>();
+
+    let normals = attribute("NORMAL")?;
+    if let Some((normals, normalized)) = normals {
+        let normals = read_attribute::<3>(normals, normalized, points)?;
+        for (vertex, normal) in vertices.iter_mut().zip(normals) {
+            vertex.normal = normal;
+        }
+    }
+    let tex_coords = attribute("TEXCOORD_0")?;
+    if let Some((tex_coords, normalized)) = tex_coords {
+        let tex_coords = read_attribute::<2>(tex_coords, normalized, points)?;
+        for (vertex, tex_coord) in vertices.iter_mut().zip(tex_coords) {
+            vertex.tex_coords = tex_coord;
+        }
+    }
+    if let Some((tex_coords, normalized)) = attribute("TEXCOORD_1")? {
+        let tex_coords = read_attribute::<2>(tex_coords, normalized, points)?;
+        for (vertex, tex_coord) in vertices.iter_mut().zip(tex_coords) {
+            vertex.tex_coords_1 = tex_coord;
+        }
+    }
+    if let Some((colors, normalized)) = attribute("COLOR_0")? {
+        // RGB colors are opaque
+        let colors = match colors.get_num_components() {
+            3 => read_attribute::<3>(colors, normalized, points)?
+                .into_iter()
+                .map(|[r, g, b]| [r, g, b, 1.0])
+                .collect(),
+            _ => read_attribute::<4>(colors, normalized, points)?,
+        };
+        for (vertex, color) in vertices.iter_mut().zip(colors) {
+            vertex.color = color;
+        }
+    }
+    let tangents = attribute("TANGENT")?;
+    if let Some((tangents, normalized)) = tangents {
+        let tangents = read_attribute::<4>(tangents, normalized, points)?;
+        for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+            vertex.tangent = tangent;
+        }
+    }
+
+    let indices = mesh
+        .faces
+        .iter()
+        .flatten()
+        .map(|&point| usize::from(point) as u32)
+        .collect();
+
+    Ok(Some(PrimitiveData {
+        vertices,
+        indices,
+        has_normals: normals.is_some(),
+        has_tex_coords: tex_coords.is_some(),
+        has_tangents: tangents.is_some(),
+    }))
+}
+
+/// The first `N` components of `attribute` for every point, as floats.
+fn read_attribute<const N: usize>(
+    attribute: &Attribute,
+    normalized: bool,
+    points: usize,
+) -> Result<Vec<[f32; N]>> {
+    let component_type = attribute.get_component_type();
+    let components = attribute.get_num_components();
+    ensure!(
+        components >= N,
+        "Draco attribute has {components} components, expected {N}"
+    );
+    ensure!(
+        attribute.len() >= points,
+        "Draco attribute has fewer values than positions"
+    );
+    let size = component_type.size();
+    let stride = components * size;
+    let data = attribute.get_data_as_bytes();
+
+    (0..points)
+        .map(|point| {
+            let value = usize::from(attribute.get_unique_val_idx(PointIdx::from(point)));
+            let bytes = data
+                .get(value * stride..(value + 1) * stride)
+                .context("Draco attribute value is out of bounds")?;
+            let mut out = [0.0; N];
+            for (i, component) in out.iter_mut().enumerate() {
+                *component =
+                    read_component(&bytes[i * size..(i + 1) * size], component_type, normalized)?;
+            }
+            Ok(out)
+        })
+        .collect()
+}
+
+/// One component in native byte order, normalized integers map to [0, 1] or
+/// [-1, 1] like glTF accessors.
+fn read_component(
+    bytes: &[u8],
+    component_type: ComponentDataType,
+    normalized: bool,
+) -> Result<f32> {
+    use gltf::accessor::DataType;
+
+    let (value, data_type) = match component_type {
+        ComponentDataType::F32 => return Ok(f32::from_ne_bytes(bytes.try_into()?)),
+        ComponentDataType::U8 => (bytes[0] as f32, DataType::U8),
+        ComponentDataType::I8 => (bytes[0] as i8 as f32, DataType::I8),
+        ComponentDataType::U16 => (u16::from_ne_bytes(bytes.try_into()?) as f32, DataType::U16),
+        ComponentDataType::I16 => (i16::from_ne_bytes(bytes.try_into()?) as f32, DataType::I16),
+        ComponentDataType::U32 => (u32::from_ne_bytes(bytes.try_into()?) as f32, DataType::U32),
+        component_type => bail!("unsupported Draco component type {component_type:?}"),
+    };
+    Ok(dequantize(value, data_type, normalized))
+}
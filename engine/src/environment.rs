@@ -0,0 +1,640 @@
+use anyhow::*;
+use image::{Rgba, Rgba32FImage};
+use wgpu::util::DeviceExt;
+
+/// Edge length of the environment cubemap the other maps are baked from.
+pub const ENVIRONMENT_SIZE: u32 = 512;
+pub const IRRADIANCE_SIZE: u32 = 32;
+pub const PREFILTERED_SIZE: u32 = 256;
+/// Roughness goes from 0 at mip 0 to 1 at the last mip.
+pub const PREFILTERED_MIP_COUNT: u32 = 6;
+pub const BRDF_LUT_SIZE: u32 = 256;
+/// Equirect images are downsampled to this width before upload, four cube
+/// faces around the horizon need no more.
+const MAX_EQUIRECT_WIDTH: u32 = 4 * ENVIRONMENT_SIZE;
+
+const CUBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct EnvironmentUniform {
+    pub intensity: f32,
+    pub prefiltered_mip_count: f32,
+    pub _padding: [f32; 2],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BakeUniform {
+    face: u32,
+    roughness: f32,
+    source_mip: f32,
+    source_size: f32,
+}
+
+/// Image based lighting baked from an equirectangular HDR image: diffuse
+/// irradiance, GGX prefiltered specular and the split sum BRDF lookup table.
+pub struct Environment {
+    pub name: String,
+    pub cubemap: wgpu::Texture,
+    pub cubemap_view: wgpu::TextureView,
+    pub irradiance: wgpu::Texture,
+    pub prefiltered: wgpu::Texture,
+    pub brdf_lut: wgpu::Texture,
+    pub sampler: wgpu::Sampler,
+    pub uniform: EnvironmentUniform,
+    pub uniform_buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+/// Downsamples an equirect image wider than `max_width`, keeping its aspect.
+fn fit_equirect(img: &Rgba32FImage, max_width: u32) -> std::borrow::Cow<'_, Rgba32FImage> {
+    let (width, height) = img.dimensions();
+    if width <= max_width && height <= max_width {
+        return std::borrow::Cow::Borrowed(img);
+    }
+    let scale = max_width as f32 / width.max(height) as f32;
+    let fitted_width = ((width as f32 * scale) as u32).max(1);
+    let fitted_height = ((height as f32 * scale) as u32).max(1);
+    log::info!("Downsampling the {width}x{height} environment to {fitted_width}x{fitted_height}");
+    std::borrow::Cow::Owned(image::imageops::resize(
+        img,
+        fitted_width,
+        fitted_height,
+        image::imageops::FilterType::Triangle,
+    ))
+}
+
+impl Environment {
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let cube = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::Cube,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                cube(0),
+                cube(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("environment_bind_group_layout"),
+        })
+    }
+
+    /// Decodes a Radiance HDR or OpenEXR equirectangular image.
+    pub fn from_bytes(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        bytes: &[u8],
+        label: &str,
+    ) -> Result<Self> {
+        let img = image::load_from_memory(bytes)?;
+        Ok(Self::from_equirect(
+            device,
+            queue,
+            layout,
+            &img.to_rgba32f(),
+            label,
+        ))
+    }
+
+    /// A neutral studio sky used until an environment is loaded.
+    pub fn studio(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let (width, height) = (256, 128);
+        let img = Rgba32FImage::from_fn(width, height, |_, y| {
+            // 1 at the zenith, -1 at the nadir
+            let up = 1.0 - 2.0 * (y as f32 + 0.5) / height as f32;
+            let sky = [0.6, 0.65, 0.75];
+            let horizon = [0.5, 0.5, 0.5];
+            let ground = [0.15, 0.14, 0.13];
+            let (from, to, t) = if up >= 0.0 {
+                (horizon, sky, up.powf(0.5))
+            } else {
+                (horizon, ground, (-up).powf(0.5))
+            };
+            let mix = |i: usize| from[i] + (to[i] - from[i]) * t;
+            Rgba([mix(0), mix(1), mix(2), 1.0])
+        });
+        Self::from_equirect(device, queue, layout, &img, "studio")
+    }
+
+    pub fn from_equirect(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        img: &Rgba32FImage,
+        label: &str,
+    ) -> Self {
+        let baker = Baker::new(device);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+
+        // Equirect to cubemap, then a mip chain to filter the other maps from
+        let environment_mips = ENVIRONMENT_SIZE.ilog2() + 1;
+        let cubemap = create_cube_texture(device, label, ENVIRONMENT_SIZE, environment_mips);
+        let max_width = MAX_EQUIRECT_WIDTH.min(device.limits().max_texture_dimension_2d);
+        let equirect = baker.upload_equirect(device, queue, &fit_equirect(img, max_width), label);
+        for face in 0..6 {
+            baker.bake(
+                device,
+                &mut encoder,
+                &baker.equirect_pipeline,
+                &equirect,
+                &cube_face_view(&cubemap, face, 0),
+                BakeUniform {
+                    face,
+                    roughness: 0.0,
+                    source_mip: 0.0,
+                    source_size: 0.0,
+                },
+            );
+        }
+        for mip in 1..environment_mips {
+            let source = baker.cube_source(device, &cubemap, mip - 1, 1);
+            for face in 0..6 {
+                baker.bake(
+                    device,
+                    &mut encoder,
+                    &baker.downsample_pipeline,
+                    &source,
+                    &cube_face_view(&cubemap, face, mip),
+                    BakeUniform {
+                        face,
+                        roughness: 0.0,
+                        source_mip: 0.0,
+                        source_size: 0.0,
+                    },
+                );
+            }
+        }
+        let source = baker.cube_source(device, &cubemap, 0, environment_mips);
+
+        let irradiance = create_cube_texture(device, "irradiance", IRRADIANCE_SIZE, 1);
+        for face in 0..6 {
+            baker.bake(
+                device,
+                &mut encoder,
+                &baker.irradiance_pipeline,
+                &source,
+                &cube_face_view(&irradiance, face, 0),
+                BakeUniform {
+                    face,
+                    roughness: 0.0,
+                    source_mip: (ENVIRONMENT_SIZE / IRRADIANCE_SIZE).ilog2() as f32,
+                    source_size: ENVIRONMENT_SIZE as f32,
+                },
+            );
+        }
+
+        let prefiltered = create_cube_texture(
+            device,
+            "prefiltered",
+            PREFILTERED_SIZE,
+            PREFILTERED_MIP_COUNT,
+        );
+        for mip in 0..PREFILTERED_MIP_COUNT {
+            for face in 0..6 {
+                baker.bake(
+                    device,
+                    &mut encoder,
+                    &baker.prefilter_pipeline,
+                    &source,
+                    &cube_face_view(&prefiltered, face, mip),
+                    BakeUniform {
+                        face,
+                        roughness: mip as f32 / (PREFILTERED_MIP_COUNT - 1) as f32,
+                        source_mip: 0.0,
+                        source_size: ENVIRONMENT_SIZE as f32,
+                    },
+                );
+            }
+        }
+
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("brdf_lut"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        baker.bake_brdf_lut(
+            &mut encoder,
+            &brdf_lut.create_view(&wgpu::TextureViewDescriptor::default()),
+        );
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform = EnvironmentUniform {
+            intensity: 1.0,
+            prefiltered_mip_count: PREFILTERED_MIP_COUNT as f32,
+            _padding: [0.0; 2],
+        };
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let cube_view = |texture: &wgpu::Texture| {
+            texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::Cube),
+                ..Default::default()
+            })
+        };
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&cube_view(&irradiance)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&cube_view(&prefiltered)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(
+                        &brdf_lut.create_view(&wgpu::TextureViewDescriptor::default()),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("environment_bind_group"),
+        });
+
+        Self {
+            name: label.to_string(),
+            cubemap_view: cube_view(&cubemap),
+            cubemap,
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            sampler,
+            uniform,
+            uniform_buffer,
+            bind_group,
+        }
+    }
+
+    /// Scales the light coming from the environment.
+    pub fn set_intensity(&mut self, queue: &wgpu::Queue, intensity: f32) {
+        self.uniform.intensity = intensity;
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniform]),
+        );
+    }
+}
+
+fn create_cube_texture(
+    device: &wgpu::Device,
+    label: &str,
+    size: u32,
+    mip_level_count: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: CUBE_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    })
+}
+
+fn cube_face_view(texture: &wgpu::Texture, face: u32, mip: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip,
+        mip_level_count: Some(1),
+        base_array_layer: face,
+        array_layer_count: Some(1),
+        ..Default::default()
+    })
+}
+
+/// Pipelines of shaders/environment.wgsl, only alive while baking.
+struct Baker {
+    bake_layout: wgpu::BindGroupLayout,
+    equirect_layout: wgpu::BindGroupLayout,
+    cube_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    equirect_pipeline: wgpu::RenderPipeline,
+    downsample_pipeline: wgpu::RenderPipeline,
+    irradiance_pipeline: wgpu::RenderPipeline,
+    prefilter_pipeline: wgpu::RenderPipeline,
+    brdf_lut_pipeline: wgpu::RenderPipeline,
+}
+
+impl Baker {
+    fn new(device: &wgpu::Device) -> Self {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Environment Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/environment.wgsl").into()),
+        });
+        let bake_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("bake_bind_group_layout"),
+        });
+        // Rgba32Float is not filterable without an extra feature, the shader
+        // filters it by hand
+        let equirect_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            }],
+            label: Some("equirect_bind_group_layout"),
+        });
+        let cube_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("cube_source_bind_group_layout"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let pipeline =
+            |entry_point: &str, layouts: &[&wgpu::BindGroupLayout], format: wgpu::TextureFormat| {
+                let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some(entry_point),
+                    bind_group_layouts: layouts,
+                    push_constant_ranges: &[],
+                });
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(entry_point),
+                    layout: Some(&layout),
+                    vertex: wgpu::VertexState {
+                        module: &module,
+                        entry_point: "vs_fullscreen",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &module,
+                        entry_point,
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            };
+
+        Self {
+            equirect_pipeline: pipeline(
+                "fs_equirect",
+                &[&bake_layout, &equirect_layout],
+                CUBE_FORMAT,
+            ),
+            downsample_pipeline: pipeline(
+                "fs_downsample",
+                &[&bake_layout, &cube_layout],
+                CUBE_FORMAT,
+            ),
+            irradiance_pipeline: pipeline(
+                "fs_irradiance",
+                &[&bake_layout, &cube_layout],
+                CUBE_FORMAT,
+            ),
+            prefilter_pipeline: pipeline(
+                "fs_prefilter",
+                &[&bake_layout, &cube_layout],
+                CUBE_FORMAT,
+            ),
+            brdf_lut_pipeline: pipeline("fs_brdf_lut", &[], BRDF_LUT_FORMAT),
+            bake_layout,
+            equirect_layout,
+            cube_layout,
+            sampler,
+        }
+    }
+
+    fn upload_equirect(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &Rgba32FImage,
+        label: &str,
+    ) -> wgpu::BindGroup {
+        let (width, height) = img.dimensions();
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            bytemuck::cast_slice(img.as_raw()),
+        );
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.equirect_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            }],
+            label: Some("equirect_bind_group"),
+        })
+    }
+
+    fn cube_source(
+        &self,
+        device: &wgpu::Device,
+        texture: &wgpu::Texture,
+        base_mip_level: u32,
+        mip_level_count: u32,
+    ) -> wgpu::BindGroup {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            base_mip_level,
+            mip_level_count: Some(mip_level_count),
+            ..Default::default()
+        });
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.cube_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+            label: Some("cube_source_bind_group"),
+        })
+    }
+
+    fn bake(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        source: &wgpu::BindGroup,
+        target: &wgpu::TextureView,
+        uniform: BakeUniform,
+    ) {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Bake Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bake_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("bake_bind_group"),
+        });
+        let mut render_pass = begin_bake_pass(encoder, target);
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.set_bind_group(1, source, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    fn bake_brdf_lut(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        let mut render_pass = begin_bake_pass(encoder, target);
+        render_pass.set_pipeline(&self.brdf_lut_pipeline);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+fn begin_bake_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    target: &'a wgpu::TextureView,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Bake Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    })
+}
@@ -0,0 +1,324 @@
+use cgmath::InnerSpace;
+
+use crate::utils::Vertex;
+
+/// How to fill in normals for primitives that ship without a NORMAL attribute.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NormalGeneration {
+    /// One normal per triangle, what the glTF spec asks for
+    #[default]
+    Flat,
+    /// Area weighted average of the triangles sharing a vertex
+    Smooth,
+}
+
+/// The indices of a primitive, counting up when it is unindexed.
+fn triangle_indices(vertices: &[Vertex], indices: &[u32]) -> Vec<u32> {
+    if indices.is_empty() {
+        (0..vertices.len() as u32).collect()
+    } else {
+        indices.to_vec()
+    }
+}
+
+/// Triangle list indices for a TRIANGLE_STRIP primitive. Every other triangle
+/// swaps its first two corners to keep the winding.
+pub fn strip_to_list(vertices: &[Vertex], indices: &[u32]) -> Vec<u32> {
+    let strip = triangle_indices(vertices, indices);
+    let mut list = Vec::with_capacity(strip.len().saturating_sub(2) * 3);
+    for (i, triangle) in strip.windows(3).enumerate() {
+        if i % 2 == 0 {
+            list.extend_from_slice(triangle);
+        } else {
+            list.extend_from_slice(&[triangle[1], triangle[0], triangle[2]]);
+        }
+    }
+    list
+}
+
+/// Triangle list indices for a TRIANGLE_FAN primitive, every triangle shares
+/// the first vertex.
+pub fn fan_to_list(vertices: &[Vertex], indices: &[u32]) -> Vec<u32> {
+    let fan = triangle_indices(vertices, indices);
+    let mut list = Vec::with_capacity(fan.len().saturating_sub(2) * 3);
+    if let Some((&center, rest)) = fan.split_first() {
+        for edge in rest.windows(2) {
+            list.extend_from_slice(&[center, edge[0], edge[1]]);
+        }
+    }
+    list
+}
+
+/// Line strip indices for a LINE_LOOP primitive, closed by repeating the
+/// first vertex.
+pub fn loop_to_strip(vertices: &[Vertex], indices: &[u32]) -> Vec<u32> {
+    let mut strip = triangle_indices(vertices, indices);
+    if let Some(&first) = strip.first() {
+        strip.push(first);
+    }
+    strip
+}
+
+fn face_normal(a: &Vertex, b: &Vertex, c: &Vertex) -> cgmath::Vector3<f32> {
+    let a = cgmath::Vector3::from(a.position);
+    let b = cgmath::Vector3::from(b.position);
+    let c = cgmath::Vector3::from(c.position);
+    // Not normalized, the length is twice the triangle area
+    (b - a).cross(c - a)
+}
+
+/// Gives every triangle its own three vertices carrying the face normal.
+/// `indices` is rewritten to match the new vertex order.
+pub fn generate_flat_normals(vertices: &mut Vec<Vertex>, indices: &mut Vec<u32>) {
+    let mut flat_vertices = Vec::with_capacity(indices.len().max(vertices.len()));
+    for triangle in triangle_indices(vertices, indices).chunks_exact(3) {
+        let corners = [
+            vertices[triangle[0] as usize],
+            vertices[triangle[1] as usize],
+            vertices[triangle[2] as usize],
+        ];
+        let normal = face_normal(&corners[0], &corners[1], &corners[2]);
+        let normal = if normal.magnitude2() > 0.0 {
+            normal.normalize()
+        } else {
+            normal
+        };
+        for mut vertex in corners {
+            vertex.normal = normal.into();
+            flat_vertices.push(vertex);
+        }
+    }
+    *indices = (0..flat_vertices.len() as u32).collect();
+    *vertices = flat_vertices;
+}
+
+/// Averages the face normals around each vertex, keeping the index buffer.
+pub fn generate_smooth_normals(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut normals = vec![cgmath::Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in triangle_indices(vertices, indices).chunks_exact(3) {
+        let normal = face_normal(
+            &vertices[triangle[0] as usize],
+            &vertices[triangle[1] as usize],
+            &vertices[triangle[2] as usize],
+        );
+        for index in triangle {
+            normals[*index as usize] += normal;
+        }
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        if normal.magnitude2() > 0.0 {
+            vertex.normal = normal.normalize().into();
+        }
+    }
+}
+
+/// Indexed triangle list view of a primitive for the MikkTSpace generator.
+struct TangentGeometry<'a> {
+    vertices: &'a mut [Vertex],
+    indices: &'a [u32],
+}
+
+impl TangentGeometry<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &Vertex {
+        &self.vertices[self.indices[face * 3 + vert] as usize]
+    }
+}
+
+impl bevy_mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        // glTF puts the UV origin top left, MikkTSpace expects bottom left
+        let tex_coords = self.vertex(face, vert).tex_coords;
+        [tex_coords[0], 1.0 - tex_coords[1]]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        let index = self.indices[face * 3 + vert] as usize;
+        self.vertices[index].tangent = tangent;
+    }
+}
+
+/// Fills `Vertex::tangent` with MikkTSpace tangents, as the glTF spec asks
+/// for when a primitive has a normal texture but no TANGENT attribute.
+/// Needs normals and texture coordinates to already be present.
+pub fn generate_tangents(vertices: &mut [Vertex], indices: &[u32]) -> bool {
+    let indices = triangle_indices(vertices, indices);
+    bevy_mikktspace::generate_tangents(&mut TangentGeometry {
+        vertices,
+        indices: &indices,
+    })
+}
+
+/// Axis aligned bounding box, used to fit shadow maps around the scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: cgmath::Point3<f32>,
+    pub max: cgmath::Point3<f32>,
+}
+
+impl Aabb {
+    /// An inverted box, extending it with any point makes it valid.
+    pub fn empty() -> Self {
+        Self {
+            min: cgmath::Point3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: cgmath::Point3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn extend(&mut self, point: cgmath::Point3<f32>) {
+        self.min = cgmath::Point3::new(
+            self.min.x.min(point.x),
+            self.min.y.min(point.y),
+            self.min.z.min(point.z),
+        );
+        self.max = cgmath::Point3::new(
+            self.max.x.max(point.x),
+            self.max.y.max(point.y),
+            self.max.z.max(point.z),
+        );
+    }
+
+    pub fn center(&self) -> cgmath::Point3<f32> {
+        cgmath::EuclideanSpace::midpoint(self.min, self.max)
+    }
+
+    pub fn corners(&self) -> [cgmath::Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            cgmath::Point3::new(a.x, a.y, a.z),
+            cgmath::Point3::new(b.x, a.y, a.z),
+            cgmath::Point3::new(a.x, b.y, a.z),
+            cgmath::Point3::new(b.x, b.y, a.z),
+            cgmath::Point3::new(a.x, a.y, b.z),
+            cgmath::Point3::new(b.x, a.y, b.z),
+            cgmath::Point3::new(a.x, b.y, b.z),
+            cgmath::Point3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    /// The box around this one after `transform`.
+    pub fn transform(&self, transform: &cgmath::Matrix4<f32>) -> Self {
+        use cgmath::Transform;
+        let mut aabb = Self::empty();
+        for corner in self.corners() {
+            aabb.extend(transform.transform_point(corner));
+        }
+        aabb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertices(positions: &[[f32; 2]]) -> Vec<Vertex> {
+        positions
+            .iter()
+            .map(|&[x, y]| Vertex {
+                position: [x, y, 0.0],
+                ..bytemuck::Zeroable::zeroed()
+            })
+            .collect()
+    }
+
+    /// Every triangle of a list faces +Z, the winding the primitive started with.
+    fn assert_counter_clockwise(vertices: &[Vertex], list: &[u32]) {
+        for triangle in list.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| &vertices[triangle[i] as usize]);
+            assert!(face_normal(a, b, c).z > 0.0, "{triangle:?} is clockwise");
+        }
+    }
+
+    #[test]
+    fn strip_keeps_the_winding() {
+        // Zigzag along a ribbon: 0 2 4 on top, 1 3 5 on the bottom
+        let vertices = vertices(&[
+            [0.0, 1.0],
+            [0.0, 0.0],
+            [1.0, 1.0],
+            [1.0, 0.0],
+            [2.0, 1.0],
+            [2.0, 0.0],
+        ]);
+        let list = strip_to_list(&vertices, &[]);
+        assert_eq!(list, [0, 1, 2, 2, 1, 3, 2, 3, 4, 4, 3, 5]);
+        assert_counter_clockwise(&vertices, &list);
+    }
+
+    #[test]
+    fn strip_uses_the_indices() {
+        let vertices = vertices(&[[1.0, 0.0], [0.0, 1.0], [1.0, 1.0], [0.0, 0.0]]);
+        let list = strip_to_list(&vertices, &[1, 3, 2, 0]);
+        assert_eq!(list, [1, 3, 2, 2, 3, 0]);
+        assert_counter_clockwise(&vertices, &list);
+    }
+
+    #[test]
+    fn fan_keeps_the_winding() {
+        let vertices = vertices(&[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0], [-1.0, 1.0]]);
+        let list = fan_to_list(&vertices, &[]);
+        assert_eq!(list, [0, 1, 2, 0, 2, 3, 0, 3, 4]);
+        assert_counter_clockwise(&vertices, &list);
+    }
+
+    #[test]
+    fn short_primitives_have_no_triangles() {
+        let vertices = vertices(&[[0.0, 0.0], [1.0, 0.0]]);
+        assert!(strip_to_list(&vertices, &[]).is_empty());
+        assert!(fan_to_list(&vertices, &[]).is_empty());
+        assert!(fan_to_list(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn unindexed_primitives_get_tangents() {
+        let mut vertices = vertices(&[
+            [0.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 1.0],
+            [0.0, 0.0],
+            [1.0, 1.0],
+            [0.0, 1.0],
+        ]);
+        for vertex in &mut vertices {
+            vertex.normal = [0.0, 0.0, 1.0];
+            vertex.tex_coords = [vertex.position[0], 1.0 - vertex.position[1]];
+        }
+        assert!(generate_tangents(&mut vertices, &[]));
+        for vertex in &vertices {
+            let tangent =
+                cgmath::Vector3::new(vertex.tangent[0], vertex.tangent[1], vertex.tangent[2]);
+            assert!(
+                tangent.magnitude2() > 0.0,
+                "{:?} has no tangent",
+                vertex.position
+            );
+        }
+    }
+
+    #[test]
+    fn loop_closes_on_the_first_vertex() {
+        let vertices = vertices(&[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]]);
+        assert_eq!(loop_to_strip(&vertices, &[]), [0, 1, 2, 0]);
+        assert_eq!(loop_to_strip(&vertices, &[2, 0, 1]), [2, 0, 1, 2]);
+        assert!(loop_to_strip(&[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,465 @@
+//! KTX2 texture containers. Basis Universal (UASTC and ETC1S) payloads are
+//! transcoded to a compressed format the device can sample, with RGBA8 as the
+//! fallback. Payloads already in a GPU format are uploaded as stored, which is
+//! all the web build takes as it has no transcoder.
+
+use std::io::Read;
+
+use anyhow::*;
+use image::RgbaImage;
+use ktx2::{ColorModel, Format, SupercompressionScheme};
+
+use crate::texture::{ColorSpace, Texture};
+
+const KTX2_MAGIC: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+
+pub fn is_ktx2(bytes: &[u8]) -> bool {
+    bytes.starts_with(&KTX2_MAGIC)
+}
+
+/// GPU format family a KTX2 texture ends up in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Bc7,
+    Astc,
+    Etc2,
+    Rgba8,
+}
+
+impl Target {
+    /// The compressed format the device samples, BC7 first as desktop GPUs
+    /// that expose ASTC or ETC2 usually emulate them.
+    fn for_device(device: &wgpu::Device) -> Self {
+        [Self::Bc7, Self::Astc, Self::Etc2]
+            .into_iter()
+            .find(|target| device.features().contains(target.feature()))
+            .unwrap_or(Self::Rgba8)
+    }
+
+    fn feature(self) -> wgpu::Features {
+        match self {
+            Self::Bc7 => wgpu::Features::TEXTURE_COMPRESSION_BC,
+            Self::Astc => wgpu::Features::TEXTURE_COMPRESSION_ASTC,
+            Self::Etc2 => wgpu::Features::TEXTURE_COMPRESSION_ETC2,
+            Self::Rgba8 => wgpu::Features::empty(),
+        }
+    }
+
+    fn format(self, color_space: ColorSpace) -> wgpu::TextureFormat {
+        let srgb = color_space == ColorSpace::Srgb;
+        match self {
+            Self::Bc7 if srgb => wgpu::TextureFormat::Bc7RgbaUnormSrgb,
+            Self::Bc7 => wgpu::TextureFormat::Bc7RgbaUnorm,
+            Self::Astc => wgpu::TextureFormat::Astc {
+                block: wgpu::AstcBlock::B4x4,
+                channel: if srgb {
+                    wgpu::AstcChannel::UnormSrgb
+                } else {
+                    wgpu::AstcChannel::Unorm
+                },
+            },
+            Self::Etc2 if srgb => wgpu::TextureFormat::Etc2Rgba8UnormSrgb,
+            Self::Etc2 => wgpu::TextureFormat::Etc2Rgba8Unorm,
+            Self::Rgba8 => color_space.format(),
+        }
+    }
+}
+
+/// Creates a texture from a KTX2 file. The texture role decides the color
+/// space, like for png and jpeg images.
+pub fn texture_from_ktx2(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    bytes: &[u8],
+    label: &str,
+    color_space: ColorSpace,
+) -> Result<Texture> {
+    let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("{label}: invalid KTX2: {e:?}"))?;
+    let header = reader.header();
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count != 1 {
+        bail!("{label}: only 2D KTX2 textures are supported");
+    }
+    let width = header.pixel_width;
+    let height = header.pixel_height.max(1);
+
+    // Universal textures have no format until they are transcoded
+    let mut target = match header.format {
+        None => match reader.color_model() {
+            Some(ColorModel::UASTC | ColorModel::ETC1S) => None,
+            model => bail!("{label}: unsupported KTX2 color model {model:?}"),
+        },
+        Some(Format::R8G8B8A8_UNORM | Format::R8G8B8A8_SRGB) => Some(Target::Rgba8),
+        Some(Format::BC7_UNORM_BLOCK | Format::BC7_SRGB_BLOCK) => Some(Target::Bc7),
+        Some(Format::ASTC_4x4_UNORM_BLOCK | Format::ASTC_4x4_SRGB_BLOCK) => Some(Target::Astc),
+        Some(Format::ETC2_R8G8B8A8_UNORM_BLOCK | Format::ETC2_R8G8B8A8_SRGB_BLOCK) => {
+            Some(Target::Etc2)
+        }
+        Some(format) => bail!("{label}: unsupported KTX2 format {format:?}"),
+    };
+
+    let mut levels = match target {
+        Some(target) => {
+            if !device.features().contains(target.feature()) {
+                bail!("{label}: the device can't sample {target:?} textures");
+            }
+            decompress_levels(&reader)
+                .with_context(|| format!("{label}: can't decompress KTX2 levels"))?
+        }
+        None => {
+            // Block compressed textures need whole blocks in the base level
+            let mut transcode_to = Target::for_device(device);
+            if width % 4 != 0 || height % 4 != 0 {
+                transcode_to = Target::Rgba8;
+            }
+            target = Some(transcode_to);
+            if reader.color_model() == Some(ColorModel::ETC1S) {
+                transcode_etc1s(&reader, transcode_to)
+                    .with_context(|| format!("{label}: can't transcode ETC1S levels"))?
+            } else {
+                let has_alpha = uastc_has_alpha(&reader);
+                let mut levels = decompress_levels(&reader)
+                    .with_context(|| format!("{label}: can't decompress KTX2 levels"))?;
+                for (level, data) in levels.iter_mut().enumerate() {
+                    let (level_width, level_height) = mip_size(width, height, level as u32);
+                    *data =
+                        transcode_uastc(data, level_width, level_height, has_alpha, transcode_to)
+                            .with_context(|| format!("{label}: can't transcode level {level}"))?;
+                }
+                levels
+            }
+        }
+    };
+    let target = target.unwrap();
+
+    // A lone RGBA8 level goes through the image path, which builds the mip chain
+    if target == Target::Rgba8 && levels.len() == 1 {
+        let image = RgbaImage::from_raw(width, height, levels.remove(0))
+            .with_context(|| format!("{label}: KTX2 level 0 is too small"))?;
+        return Texture::from_image(device, queue, &image, Some(label), color_space);
+    }
+
+    let format = target.format(color_space);
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size,
+        mip_level_count: levels.len() as u32,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    let (block_width, block_height) = format.block_dimensions();
+    let block_size = format.block_size(None).unwrap_or(4);
+    for (level, data) in levels.iter().enumerate() {
+        let level_size = size.mip_level_size(level as u32, wgpu::TextureDimension::D2);
+        let blocks_x = level_size.width.div_ceil(block_width);
+        let blocks_y = level_size.height.div_ceil(block_height);
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: level as u32,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(blocks_x * block_size),
+                rows_per_image: Some(blocks_y),
+            },
+            level_size.physical_size(format),
+        );
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    Ok(Texture {
+        texture,
+        view,
+        sampler: None,
+    })
+}
+
+/// Whether the UASTC data carries alpha, from the channel of the first DFD
+/// sample (RGBA or RRRG).
+fn uastc_has_alpha(reader: &ktx2::Reader<&[u8]>) -> bool {
+    const UASTC_RGBA: u8 = 3;
+    const UASTC_RRRG: u8 = 5;
+    reader
+        .basic_dfd()
+        .and_then(|dfd| dfd.sample_information.first())
+        .is_some_and(|sample| matches!(sample.channel_type, UASTC_RGBA | UASTC_RRRG))
+}
+
+fn mip_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+fn decompress_levels(reader: &ktx2::Reader<&[u8]>) -> Result<Vec<Vec<u8>>> {
+    let scheme = reader.header().supercompression_scheme;
+    reader
+        .levels()
+        .map(|level| decompress(scheme, level.data))
+        .collect()
+}
+
+fn decompress(scheme: Option<SupercompressionScheme>, data: &[u8]) -> Result<Vec<u8>> {
+    match scheme {
+        None => Ok(data.to_vec()),
+        Some(SupercompressionScheme::Zstandard) => {
+            let mut decoder = ruzstd::decoding::StreamingDecoder::new(data)
+                .map_err(|e| anyhow!("invalid zstd stream: {e}"))?;
+            let mut decompressed = Vec::new();
+            decoder.read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        Some(scheme) => bail!("unsupported KTX2 supercompression {scheme:?}"),
+    }
+}
+
+/// Whether the ETC1S data carries alpha, stored as a second slice with its
+/// own DFD sample.
+fn etc1s_has_alpha(reader: &ktx2::Reader<&[u8]>) -> bool {
+    reader
+        .basic_dfd()
+        .is_some_and(|dfd| dfd.sample_information.len() > 1)
+}
+
+/// The `.basis` file version the transcoder reads, and the size of its header
+/// and of each slice description.
+const BASIS_VERSION: u16 = 0x13;
+const BASIS_HEADER_SIZE: usize = 77;
+const BASIS_SLICE_DESC_SIZE: usize = 23;
+
+/// Repackages BasisLZ levels as a `.basis` file, the container the ETC1S
+/// transcoder reads. The KTX2 global data holds the codebooks and where each
+/// level's color and alpha slices are.
+fn etc1s_to_basis(reader: &ktx2::Reader<&[u8]>) -> Result<Vec<u8>> {
+    let header = reader.header();
+    let sgd = reader.supercompression_global_data();
+    ensure!(!sgd.is_empty(), "missing BasisLZ global data");
+    let read_u16 = |offset: usize| -> Result<u32> {
+        let bytes = sgd
+            .get(offset..offset + 2)
+            .context("BasisLZ global data is truncated")?;
+        Ok(u16::from_le_bytes(bytes.try_into().unwrap()) as u32)
+    };
+    let read_u32 = |offset: usize| -> Result<u32> {
+        let bytes = sgd
+            .get(offset..offset + 4)
+            .context("BasisLZ global data is truncated")?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    let endpoint_count = read_u16(0)?;
+    let selector_count = read_u16(2)?;
+    let endpoints_length = read_u32(4)? as usize;
+    let selectors_length = read_u32(8)? as usize;
+    let tables_length = read_u32(12)? as usize;
+    let extended_length = read_u32(16)? as usize;
+    let has_alpha = etc1s_has_alpha(reader);
+    let level_count = header.level_count.max(1) as usize;
+
+    // One image description per level, each with byte offsets into the level
+    let mut slices = Vec::new();
+    for (level, data) in reader.levels().enumerate() {
+        let desc = 20 + level * 20;
+        let (level_width, level_height) =
+            mip_size(header.pixel_width, header.pixel_height.max(1), level as u32);
+        let mut push_slice = |offset: usize, length: usize, alpha: bool| -> Result<()> {
+            let data = data
+                .data
+                .get(offset..offset + length)
+                .context("BasisLZ slice is outside its level")?;
+            slices.push((level as u32, level_width, level_height, alpha, data));
+            Ok(())
+        };
+        push_slice(
+            read_u32(desc + 4)? as usize,
+            read_u32(desc + 8)? as usize,
+            false,
+        )?;
+        if has_alpha {
+            push_slice(
+                read_u32(desc + 12)? as usize,
+                read_u32(desc + 16)? as usize,
+                true,
+            )?;
+        }
+    }
+    let codebooks_start = 20 + level_count * 20;
+    let codebooks_length = endpoints_length + selectors_length + tables_length + extended_length;
+    let codebooks = sgd
+        .get(codebooks_start..codebooks_start + codebooks_length)
+        .context("BasisLZ global data is truncated")?;
+
+    let slice_descs_start = BASIS_HEADER_SIZE;
+    let endpoints_start = slice_descs_start + slices.len() * BASIS_SLICE_DESC_SIZE;
+    let selectors_start = endpoints_start + endpoints_length;
+    let tables_start = selectors_start + selectors_length;
+    let extended_start = tables_start + tables_length;
+    let mut slice_start = extended_start + extended_length;
+
+    let mut data = Vec::new();
+    for &(level, width, height, alpha, slice) in &slices {
+        data.extend_from_slice(&[0; 3]); // image index
+        data.push(level as u8);
+        data.push(alpha as u8);
+        data.extend_from_slice(&(width as u16).to_le_bytes());
+        data.extend_from_slice(&(height as u16).to_le_bytes());
+        data.extend_from_slice(&(width.div_ceil(4) as u16).to_le_bytes());
+        data.extend_from_slice(&(height.div_ceil(4) as u16).to_le_bytes());
+        data.extend_from_slice(&(slice_start as u32).to_le_bytes());
+        data.extend_from_slice(&(slice.len() as u32).to_le_bytes());
+        data.extend_from_slice(&crc16(slice).to_le_bytes());
+        slice_start += slice.len();
+    }
+    data.extend_from_slice(codebooks);
+    for (.., slice) in &slices {
+        data.extend_from_slice(slice);
+    }
+
+    // Flags: ETC1S, alpha slices
+    let flags: u16 = 1 | if has_alpha { 4 } else { 0 };
+    let mut fields = Vec::with_capacity(BASIS_HEADER_SIZE);
+    let mut push = |value: u32, size: usize| fields.extend_from_slice(&value.to_le_bytes()[..size]);
+    push(data.len() as u32, 4);
+    push(crc16(&data) as u32, 2);
+    push(slices.len() as u32, 3);
+    push(1, 3); // total images
+    push(0, 1); // ETC1S
+    push(flags as u32, 2);
+    push(0, 1); // 2D
+    push(0, 3); // microseconds per frame
+    push(0, 4); // reserved
+    push(0, 4); // userdata0
+    push(0, 4); // userdata1
+    push(endpoint_count, 2);
+    push(endpoints_start as u32, 4);
+    push(endpoints_length as u32, 3);
+    push(selector_count, 2);
+    push(selectors_start as u32, 4);
+    push(selectors_length as u32, 3);
+    push(tables_start as u32, 4);
+    push(tables_length as u32, 4);
+    push(slice_descs_start as u32, 4);
+    push(extended_start as u32, 4);
+    push(extended_length as u32, 4);
+
+    let mut file = Vec::with_capacity(BASIS_HEADER_SIZE + data.len());
+    file.extend_from_slice(b"sB");
+    file.extend_from_slice(&BASIS_VERSION.to_le_bytes());
+    file.extend_from_slice(&(BASIS_HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&crc16(&fields).to_le_bytes());
+    file.extend_from_slice(&fields);
+    file.extend_from_slice(&data);
+    Ok(file)
+}
+
+/// The CRC16 `.basis` files use for their header and data.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = !0u16;
+    for &byte in data {
+        let q = byte as u16 ^ (crc >> 8);
+        let k = (q >> 4) ^ q;
+        crc = (crc << 8) ^ k ^ (k << 5) ^ (k << 12);
+    }
+    !crc
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn transcode_etc1s(reader: &ktx2::Reader<&[u8]>, target: Target) -> Result<Vec<Vec<u8>>> {
+    use basis_universal::{TranscodeParameters, Transcoder, TranscoderTextureFormat};
+
+    let format = match target {
+        Target::Bc7 => TranscoderTextureFormat::BC7_RGBA,
+        Target::Astc => TranscoderTextureFormat::ASTC_4x4_RGBA,
+        Target::Etc2 => TranscoderTextureFormat::ETC2_RGBA,
+        Target::Rgba8 => TranscoderTextureFormat::RGBA32,
+    };
+    let file = etc1s_to_basis(reader)?;
+    let mut transcoder = Transcoder::new();
+    transcoder
+        .prepare_transcoding(&file)
+        .map_err(|_| anyhow!("invalid BasisLZ codebooks"))?;
+    (0..reader.levels().count() as u32)
+        .map(|level| {
+            transcoder
+                .transcode_image_level(
+                    &file,
+                    format,
+                    TranscodeParameters {
+                        image_index: 0,
+                        level_index: level,
+                        decode_flags: None,
+                        output_row_pitch_in_blocks_or_pixels: None,
+                        output_rows_in_pixels: None,
+                    },
+                )
+                .map_err(|e| anyhow!("level {level}: {e:?}"))
+        })
+        .collect()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn transcode_etc1s(_reader: &ktx2::Reader<&[u8]>, _target: Target) -> Result<Vec<Vec<u8>>> {
+    bail!("ETC1S transcoding is not available in the web build")
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn transcode_uastc(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    target: Target,
+) -> Result<Vec<u8>> {
+    use basis_universal::{
+        DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscoderBlockFormat,
+    };
+
+    let block_format = match target {
+        Target::Bc7 => TranscoderBlockFormat::BC7,
+        Target::Astc => TranscoderBlockFormat::ASTC_4x4,
+        Target::Etc2 => TranscoderBlockFormat::ETC2_RGBA,
+        Target::Rgba8 => TranscoderBlockFormat::RGBA32,
+    };
+    let num_blocks_x = width.div_ceil(4);
+    let num_blocks_y = height.div_ceil(4);
+    ensure!(
+        data.len() >= (num_blocks_x * num_blocks_y * 16) as usize,
+        "UASTC level is too small for {width}x{height}"
+    );
+    LowLevelUastcTranscoder::new()
+        .transcode_slice(
+            data,
+            SliceParametersUastc {
+                num_blocks_x,
+                num_blocks_y,
+                has_alpha,
+                original_width: width,
+                original_height: height,
+            },
+            DecodeFlags::empty(),
+            block_format,
+        )
+        .map_err(|e| anyhow!("UASTC transcoding failed: {e:?}"))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn transcode_uastc(
+    _data: &[u8],
+    _width: u32,
+    _height: u32,
+    _has_alpha: bool,
+    _target: Target,
+) -> Result<Vec<u8>> {
+    bail!("UASTC transcoding is not available in the web build")
+}
@@ -1,7 +1,15 @@
+pub mod background;
 pub mod camera;
+pub mod draco;
+pub mod environment;
+pub mod geometry;
+pub mod ktx;
+pub mod light;
+pub mod meshopt;
 pub mod model;
 pub mod pipeline;
 pub mod resources;
+pub mod shadow;
 pub mod state;
 pub mod texture;
 pub mod utils;
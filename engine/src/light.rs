@@ -0,0 +1,276 @@
+use cgmath::{EuclideanSpace, InnerSpace};
+
+use crate::shadow::ShadowSettings;
+
+/// Upper bound of lights the shader loops over, `MAX_LIGHTS` in texture.wgsl.
+pub const MAX_LIGHTS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    /// Cone angles in radians, measured from the spot direction
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+/// A punctual light in world space, following `KHR_lights_punctual`.
+/// Directional lights are in lux, point and spot lights in candela.
+#[derive(Clone, Debug)]
+pub struct Light {
+    pub name: String,
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// Distance where point and spot lights reach zero, `None` is infinite
+    pub range: Option<f32>,
+    pub position: cgmath::Point3<f32>,
+    /// Direction the light travels in
+    pub direction: cgmath::Vector3<f32>,
+    /// Directional and spot lights cast shadows when set, point lights ignore it
+    pub shadow: Option<ShadowSettings>,
+}
+
+impl Light {
+    pub fn directional(
+        name: &str,
+        direction: cgmath::Vector3<f32>,
+        color: [f32; 3],
+        intensity: f32,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: LightKind::Directional,
+            color,
+            intensity,
+            range: None,
+            position: cgmath::Point3::origin(),
+            direction: direction.normalize(),
+            shadow: None,
+        }
+    }
+
+    pub fn point(
+        name: &str,
+        position: cgmath::Point3<f32>,
+        color: [f32; 3],
+        intensity: f32,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: LightKind::Point,
+            color,
+            intensity,
+            range: None,
+            position,
+            direction: -cgmath::Vector3::unit_z(),
+            shadow: None,
+        }
+    }
+
+    /// Places a glTF light with the world transform of the node using it.
+    /// Lights shine down the node's local -Z axis.
+    pub fn from_gltf(
+        light: &gltf::khr_lights_punctual::Light,
+        transform: &cgmath::Matrix4<f32>,
+    ) -> Self {
+        let kind = match light.kind() {
+            gltf::khr_lights_punctual::Kind::Directional => LightKind::Directional,
+            gltf::khr_lights_punctual::Kind::Point => LightKind::Point,
+            gltf::khr_lights_punctual::Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            },
+        };
+        let shadow = match kind {
+            LightKind::Point => None,
+            _ => Some(ShadowSettings::default()),
+        };
+        let direction = (transform * cgmath::Vector4::new(0.0, 0.0, -1.0, 0.0)).truncate();
+        let position = transform * cgmath::Vector4::new(0.0, 0.0, 0.0, 1.0);
+        Self {
+            name: light.name().unwrap_or("Unknown").to_string(),
+            kind,
+            color: light.color(),
+            intensity: light.intensity(),
+            range: light.range(),
+            position: cgmath::Point3::from_vec(position.truncate()),
+            direction: if direction.magnitude2() > 0.0 {
+                direction.normalize()
+            } else {
+                -cgmath::Vector3::unit_z()
+            },
+            shadow,
+        }
+    }
+
+    pub fn to_raw(&self) -> LightRaw {
+        let (kind, inner_cone_cos, outer_cone_cos) = match self.kind {
+            LightKind::Directional => (0, 1.0, 1.0),
+            LightKind::Point => (1, 1.0, 1.0),
+            LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => (2, inner_cone_angle.cos(), outer_cone_angle.cos()),
+        };
+        LightRaw {
+            position: self.position.into(),
+            kind,
+            direction: self.direction.into(),
+            range: self.range.unwrap_or(0.0),
+            color: self.color,
+            intensity: self.intensity,
+            inner_cone_cos,
+            outer_cone_cos,
+            shadow_layer: -1,
+            shadow_count: 0,
+        }
+    }
+}
+
+/// The lights used when a file brings none: key, fill and rim directional
+/// lights around the origin.
+#[derive(Clone, Debug)]
+pub struct LightRig {
+    pub color: [f32; 3],
+    pub key_intensity: f32,
+    pub fill_intensity: f32,
+    pub rim_intensity: f32,
+}
+
+impl Default for LightRig {
+    fn default() -> Self {
+        Self {
+            color: [1.0, 1.0, 1.0],
+            key_intensity: 3.0,
+            fill_intensity: 1.0,
+            rim_intensity: 2.0,
+        }
+    }
+}
+
+impl LightRig {
+    pub fn lights(&self) -> Vec<Light> {
+        vec![
+            Light {
+                shadow: Some(ShadowSettings::default()),
+                ..Light::directional(
+                    "Key Light",
+                    cgmath::Vector3::new(-0.5, -1.0, -0.8),
+                    self.color,
+                    self.key_intensity,
+                )
+            },
+            Light::directional(
+                "Fill Light",
+                cgmath::Vector3::new(0.8, -0.3, -0.6),
+                self.color,
+                self.fill_intensity,
+            ),
+            Light::directional(
+                "Rim Light",
+                cgmath::Vector3::new(0.0, -0.5, 1.0),
+                self.color,
+                self.rim_intensity,
+            ),
+        ]
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightRaw {
+    position: [f32; 3],
+    // 0 directional, 1 point, 2 spot
+    kind: u32,
+    direction: [f32; 3],
+    // 0 means infinite
+    range: f32,
+    color: [f32; 3],
+    intensity: f32,
+    inner_cone_cos: f32,
+    outer_cone_cos: f32,
+    // First layer in the shadow map array, -1 without shadows
+    shadow_layer: i32,
+    // One layer per cascade for directional lights, one for spot lights
+    shadow_count: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    lights: [LightRaw; MAX_LIGHTS],
+    count: u32,
+    _padding: [u32; 3],
+}
+
+impl LightUniform {
+    pub fn new(lights: &[Light]) -> Self {
+        let mut uniform = Self {
+            lights: [bytemuck::Zeroable::zeroed(); MAX_LIGHTS],
+            count: lights.len().min(MAX_LIGHTS) as u32,
+            _padding: [0; 3],
+        };
+        for (raw, light) in uniform.lights.iter_mut().zip(lights) {
+            *raw = light.to_raw();
+        }
+        uniform
+    }
+
+    /// Points a light at its layers in the shadow map array.
+    pub fn set_shadow(&mut self, light: usize, layer: u32, count: u32) {
+        self.lights[light].shadow_layer = layer as i32;
+        self.lights[light].shadow_count = count;
+    }
+
+    /// The lights share their group with the shadow maps: the light uniform,
+    /// the depth array, its comparison sampler and the `ShadowUniform`.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("light_bind_group_layout"),
+        })
+    }
+}
@@ -0,0 +1,133 @@
+//! Meshopt compressed bufferViews (EXT_meshopt_compression). Each compressed
+//! view is decoded into the place it would have in its buffer, so accessors
+//! read it like uncompressed data afterwards.
+
+use anyhow::*;
+use meshopt_rs::index::buffer::decode_index_buffer;
+use meshopt_rs::index::sequence::decode_index_sequence;
+use meshopt_rs::vertex::buffer::decode_vertex_buffer;
+use meshopt_rs::vertex::filter;
+
+pub const EXTENSION: &str = "EXT_meshopt_compression";
+
+/// Whether `buffer` only exists as the destination of compressed views. It
+/// has no data of its own, the loader allocates it.
+pub fn is_fallback_buffer(buffer: &gltf::Buffer) -> bool {
+    buffer
+        .extension_value(EXTENSION)
+        .is_some_and(|extension| extension["fallback"].as_bool() == Some(true))
+}
+
+/// Decodes every compressed bufferView of `document` into `buffer_data`.
+pub fn decode_buffer_views(document: &gltf::Document, buffer_data: &mut [Vec<u8>]) -> Result<()> {
+    for view in document.views() {
+        let Some(extension) = view.extension_value(EXTENSION) else {
+            continue;
+        };
+        decode_buffer_view(&view, extension, buffer_data)
+            .with_context(|| format!("can't decode meshopt bufferView {}", view.index()))?;
+    }
+    Ok(())
+}
+
+fn decode_buffer_view(
+    view: &gltf::buffer::View,
+    extension: &gltf::json::Value,
+    buffer_data: &mut [Vec<u8>],
+) -> Result<()> {
+    let field = |name: &str| {
+        extension[name]
+            .as_u64()
+            .map(|value| value as usize)
+            .with_context(|| format!("missing {name}"))
+    };
+    let source_buffer = field("buffer")?;
+    let source_offset = extension["byteOffset"].as_u64().unwrap_or(0) as usize;
+    let source_length = field("byteLength")?;
+    let stride = field("byteStride")?;
+    let count = field("count")?;
+    let mode = extension["mode"].as_str().context("missing mode")?;
+    let filter = extension["filter"].as_str().unwrap_or("NONE");
+
+    let source = buffer_data
+        .get(source_buffer)
+        .and_then(|buffer| buffer.get(source_offset..source_offset + source_length))
+        .context("compressed data is out of bounds")?;
+
+    let decoded = match mode {
+        "ATTRIBUTES" => {
+            let mut words = decode_attributes(source, stride, count)?;
+            apply_filter(&mut words, filter, stride)?;
+            bytemuck::cast_slice(&words).to_vec()
+        }
+        "TRIANGLES" | "INDICES" => {
+            ensure!(filter == "NONE", "index data can't use the {filter} filter");
+            let mut indices = vec![0u32; count];
+            let decoded = if mode == "TRIANGLES" {
+                decode_index_buffer(&mut indices, source)
+            } else {
+                decode_index_sequence(&mut indices, source)
+            };
+            decoded.map_err(|e| anyhow!("{e:?}"))?;
+            match stride {
+                2 => indices
+                    .iter()
+                    .flat_map(|&index| (index as u16).to_le_bytes())
+                    .collect(),
+                4 => bytemuck::cast_slice(&indices).to_vec(),
+                _ => bail!("index stride must be 2 or 4, not {stride}"),
+            }
+        }
+        _ => bail!("unknown mode {mode}"),
+    };
+
+    ensure!(
+        decoded.len() == view.length(),
+        "decoded {} bytes for a bufferView of {} bytes",
+        decoded.len(),
+        view.length()
+    );
+    let destination = buffer_data
+        .get_mut(view.buffer().index())
+        .and_then(|buffer| buffer.get_mut(view.offset()..view.offset() + decoded.len()))
+        .context("decoded data doesn't fit in the bufferView's buffer")?;
+    destination.copy_from_slice(&decoded);
+    Ok(())
+}
+
+/// Decodes interleaved vertex data as 32-bit words, the stride is always a
+/// multiple of 4.
+fn decode_attributes(source: &[u8], stride: usize, count: usize) -> Result<Vec<u32>> {
+    fn decode<const WORDS: usize>(source: &[u8], count: usize) -> Result<Vec<u32>> {
+        // The decoder takes the vertex size from the element type
+        let mut vertices = vec![[0u32; WORDS]; count];
+        decode_vertex_buffer(&mut vertices, source).map_err(|e| anyhow!("{e:?}"))?;
+        Ok(vertices.concat())
+    }
+
+    macro_rules! decode_by_stride {
+        ($($words:literal)*) => {
+            match stride {
+                $(stride if stride == $words * 4 => decode::<$words>(source, count),)*
+                _ => bail!("vertex stride must be a multiple of 4 up to 256, not {stride}"),
+            }
+        };
+    }
+    decode_by_stride!(
+        1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+        33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61
+        62 63 64
+    )
+}
+
+fn apply_filter(words: &mut [u32], filter: &str, stride: usize) -> Result<()> {
+    match (filter, stride) {
+        ("NONE", _) => {}
+        ("OCTAHEDRAL", 4) => filter::decode_filter_oct_8(bytemuck::cast_slice_mut(words)),
+        ("OCTAHEDRAL", 8) => filter::decode_filter_oct_16(bytemuck::cast_slice_mut(words)),
+        ("QUATERNION", 8) => filter::decode_filter_quat(bytemuck::cast_slice_mut(words)),
+        ("EXPONENTIAL", _) => filter::decode_filter_exp(words),
+        _ => bail!("unsupported filter {filter} with stride {stride}"),
+    }
+    Ok(())
+}
@@ -1,26 +1,296 @@
 use std::ops::Range;
+use std::sync::Arc;
 
+use crate::geometry::Aabb;
+use crate::light::Light;
 use crate::texture;
 
+pub use gltf::material::AlphaMode;
+
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    // The bind group layout every material of this model was created with
+    pub material_layout: Arc<wgpu::BindGroupLayout>,
+    pub nodes: Vec<Node>,
+    pub instance_buffer: wgpu::Buffer,
+    // KHR_lights_punctual lights placed in the scene
+    pub lights: Vec<Light>,
+    // World space bounds of the drawn meshes
+    pub bounds: Aabb,
+    // World transform of every instance in `instance_buffer`
+    pub instances: Vec<cgmath::Matrix4<f32>>,
+}
+
+/// A node of the default scene, flattened with its world transform.
+pub struct Node {
+    pub name: String,
+    pub mesh: Option<usize>,
+    pub transform: cgmath::Matrix4<f32>,
 }
 
 pub struct Material {
     pub name: String,
     pub index: usize,
     pub bind_group: wgpu::BindGroup,
-    pub texture_bind_group_layout: wgpu::BindGroupLayout,
+    // Shared by every material of a model so they can share pipelines
+    pub texture_bind_group_layout: Arc<wgpu::BindGroupLayout>,
+    pub uniform: MaterialUniform,
+    pub uniform_buffer: wgpu::Buffer,
+    pub alpha_mode: AlphaMode,
+    // Drawn without back face culling
+    pub double_sided: bool,
+}
+
+// `MaterialUniform::alpha_mode` values, mirrored in texture.wgsl
+const ALPHA_OPAQUE: u32 = 0;
+const ALPHA_MASK: u32 = 1;
+const ALPHA_BLEND: u32 = 2;
+
+/// Material factors in linear space, matching `MaterialUniform` in texture.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    pub base_color_factor: [f32; 4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub normal_scale: f32,
+    pub has_normal_texture: u32,
+    pub alpha_cutoff: f32,
+    pub alpha_mode: u32,
+    pub occlusion_strength: f32,
+    pub _padding: u32,
+    pub emissive_factor: [f32; 3],
+    // KHR_materials_emissive_strength, lets emission go over 1.0
+    pub emissive_strength: f32,
+    pub base_color_transform: TextureTransform,
+    pub metallic_roughness_transform: TextureTransform,
+    pub normal_transform: TextureTransform,
+    pub occlusion_transform: TextureTransform,
+    pub emissive_transform: TextureTransform,
+}
+
+/// UV set and transform of one texture slot, matching `TextureTransform` in
+/// texture.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TextureTransform {
+    // Columns of the 3x3 UV matrix, padded to vec4 like a WGSL mat3x3
+    pub matrix: [[f32; 4]; 3],
+    // Index of the TEXCOORD attribute the slot samples with
+    pub tex_coord: u32,
+    pub _padding: [u32; 3],
+}
+
+impl TextureTransform {
+    pub const EXTENSION: &'static str = "KHR_texture_transform";
+
+    /// Builds the KHR_texture_transform matrix, offset * rotation * scale. The
+    /// extension's texCoord overrides the one from the texture info.
+    pub fn new(tex_coord: u32, extension: Option<&gltf::json::Value>) -> Self {
+        let Some(extension) = extension else {
+            return Self {
+                tex_coord,
+                ..Default::default()
+            };
+        };
+        let pair = |name: &str, default: f32| {
+            let value = &extension[name];
+            [0, 1].map(|i| value[i].as_f64().map_or(default, |v| v as f32))
+        };
+        let [offset_u, offset_v] = pair("offset", 0.0);
+        let [scale_u, scale_v] = pair("scale", 1.0);
+        let rotation = extension["rotation"].as_f64().unwrap_or(0.0) as f32;
+        let (sin, cos) = rotation.sin_cos();
+        Self {
+            matrix: [
+                [cos * scale_u, -sin * scale_u, 0.0, 0.0],
+                [sin * scale_v, cos * scale_v, 0.0, 0.0],
+                [offset_u, offset_v, 1.0, 0.0],
+            ],
+            tex_coord: extension["texCoord"]
+                .as_u64()
+                .map_or(tex_coord, |tex_coord| tex_coord as u32),
+            _padding: [0; 3],
+        }
+    }
+}
+
+impl Default for TextureTransform {
+    /// Identity transform on TEXCOORD_0.
+    fn default() -> Self {
+        Self {
+            matrix: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+            ],
+            tex_coord: 0,
+            _padding: [0; 3],
+        }
+    }
+}
+
+impl MaterialUniform {
+    pub fn set_alpha_mode(&mut self, alpha_mode: AlphaMode) {
+        self.alpha_mode = match alpha_mode {
+            AlphaMode::Opaque => ALPHA_OPAQUE,
+            AlphaMode::Mask => ALPHA_MASK,
+            AlphaMode::Blend => ALPHA_BLEND,
+        };
+    }
+}
+
+impl Default for MaterialUniform {
+    fn default() -> Self {
+        // glTF defaults
+        Self {
+            base_color_factor: [1.0; 4],
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            normal_scale: 1.0,
+            has_normal_texture: 0,
+            alpha_cutoff: 0.5,
+            alpha_mode: ALPHA_OPAQUE,
+            occlusion_strength: 1.0,
+            _padding: 0,
+            emissive_factor: [0.0; 3],
+            emissive_strength: 1.0,
+            base_color_transform: Default::default(),
+            metallic_roughness_transform: Default::default(),
+            normal_transform: Default::default(),
+            occlusion_transform: Default::default(),
+            emissive_transform: Default::default(),
+        }
+    }
+}
+
+impl Material {
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("material_bind_group_layout"),
+            entries: &[
+                // Base color
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Factors
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // Metallic (B) and roughness (G)
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Tangent space normals
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Emissive
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Ambient occlusion (R)
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        })
+    }
 }
 
 pub struct Mesh {
     pub name: String,
     pub index: usize,
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    // Shared with the mirrored copy of the mesh, if any
+    pub vertex_buffer: Arc<wgpu::Buffer>,
+    // None for unindexed primitives, which draw their vertices in order
+    pub index_buffer: Option<Arc<wgpu::Buffer>>,
+    // Indices, or vertices when there is no index buffer
     pub num_elements: u32,
+    // The glTF mode, fans and loops already converted to a wgpu topology
+    pub topology: wgpu::PrimitiveTopology,
     pub material: usize,
+    // Local space bounds, used to sort transparent meshes
+    pub bounds: Aabb,
+    // Instances in `Model::instance_buffer`, one per node using this mesh
+    pub instances: Range<u32>,
+    // The instances have a negative determinant, which flips the winding
+    pub mirrored: bool,
+    // Points and lines without normals, drawn without lighting
+    pub unlit: bool,
 }
 pub trait DrawModel<'a> {
     fn draw_mesh(
@@ -59,9 +329,38 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        draw_elements(self, mesh, instances);
+    }
+}
+
+/// Depth only draws for the shadow pass, the caller binds the light view.
+pub trait DrawShadow<'a> {
+    fn draw_mesh_shadow(&mut self, mesh: &'a Mesh, instances: Range<u32>);
+}
+
+impl<'a, 'b> DrawShadow<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_mesh_shadow(&mut self, mesh: &'b Mesh, instances: Range<u32>) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        draw_elements(self, mesh, instances);
+    }
+}
+
+/// Draws `mesh` with its index buffer when it has one.
+fn draw_elements<'a>(
+    render_pass: &mut wgpu::RenderPass<'a>,
+    mesh: &'a Mesh,
+    instances: Range<u32>,
+) {
+    match &mesh.index_buffer {
+        Some(index_buffer) => {
+            render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed(0..mesh.num_elements, 0, instances);
+        }
+        None => render_pass.draw(0..mesh.num_elements, instances),
     }
 }
@@ -1,9 +1,44 @@
-use wgpu::{util::DeviceExt, BindGroupLayout, Device, ShaderModule};
+use std::collections::HashMap;
 
-use crate::{
-    texture::{self, Texture},
-    utils::{InstanceRaw, Vertex},
-};
+use wgpu::Device;
+
+use crate::texture;
+
+/// Owned, hashable copy of a `wgpu::VertexBufferLayout`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexLayoutKey {
+    pub array_stride: wgpu::BufferAddress,
+    pub step_mode: wgpu::VertexStepMode,
+    pub attributes: Vec<wgpu::VertexAttribute>,
+}
+
+impl From<&wgpu::VertexBufferLayout<'_>> for VertexLayoutKey {
+    fn from(layout: &wgpu::VertexBufferLayout<'_>) -> Self {
+        Self {
+            array_stride: layout.array_stride,
+            step_mode: layout.step_mode,
+            attributes: layout.attributes.to_vec(),
+        }
+    }
+}
+
+/// Everything that makes two mesh pipelines differ. Meshes with equal keys
+/// share one pipeline.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub material_layout: wgpu::Id<wgpu::BindGroupLayout>,
+    pub vertex_layouts: Vec<VertexLayoutKey>,
+    pub topology: wgpu::PrimitiveTopology,
+    // Clockwise for mirrored instances, their transform flips the winding
+    pub front_face: wgpu::FrontFace,
+    pub polygon_mode: wgpu::PolygonMode,
+    pub blend: Option<wgpu::BlendState>,
+    pub cull_mode: Option<wgpu::Face>,
+    // Off for blended meshes, so they don't hide what is drawn behind them
+    pub depth_write: bool,
+    // Shades with `fs_unlit`, for meshes without normals to light
+    pub unlit: bool,
+}
 
 pub struct Pipeline {
     pipeline: wgpu::RenderPipeline,
@@ -13,14 +48,12 @@ impl Pipeline {
     pub fn new(
         label: &str,
         device: &Device,
-        shader_label: &str,
-        shader_source: &str,
-        polygon_mode: &wgpu::PolygonMode,
+        module: &wgpu::ShaderModule,
+        key: &PipelineKey,
         config: &wgpu::SurfaceConfiguration,
         bind_group_layouts: &[&wgpu::BindGroupLayout],
         vertex_layouts: &[wgpu::VertexBufferLayout],
     ) -> Self {
-        let module = Self::create_shader(device, shader_label, shader_source);
         let layout = Self::create_new_layout(
             device,
             &format!("{label} Pipeline Layout"),
@@ -29,11 +62,11 @@ impl Pipeline {
         let pipeline = Self::create_new_pipeline(
             device,
             &format!("{label} Pipeline"),
-            &module,
-            &polygon_mode,
+            module,
+            key,
             config,
             &layout,
-            &vertex_layouts,
+            vertex_layouts,
         );
         return Self { pipeline: pipeline };
     }
@@ -65,7 +98,7 @@ impl Pipeline {
         device: &Device,
         label: &str,
         module: &wgpu::ShaderModule,
-        polygon_mode: &wgpu::PolygonMode,
+        key: &PipelineKey,
         config: &wgpu::SurfaceConfiguration,
         layout: &wgpu::PipelineLayout,
         vertex_layouts: &[wgpu::VertexBufferLayout],
@@ -85,27 +118,25 @@ impl Pipeline {
             fragment: Some(wgpu::FragmentState {
                 // 3.
                 module: &module,
-                entry_point: "fs_main",
+                entry_point: if key.unlit { "fs_unlit" } else { "fs_main" },
                 targets: &[Some(wgpu::ColorTargetState {
                     // 4.
                     format: config.format.add_srgb_suffix(),
-                    blend: Some(wgpu::BlendState {
-                        color: wgpu::BlendComponent::REPLACE,
-                        alpha: wgpu::BlendComponent::REPLACE,
-                    }),
+                    blend: key.blend,
 
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
             }),
             primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList, // 1.
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: *polygon_mode, // Poligon mode
+                topology: key.topology,
+                front_face: key.front_face,
+                cull_mode: key.cull_mode,
+                polygon_mode: key.polygon_mode, // Poligon mode
                 ..Default::default()
             },
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
+                depth_write_enabled: key.depth_write,
                 depth_compare: wgpu::CompareFunction::Less, // 1.
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
@@ -119,3 +150,63 @@ impl Pipeline {
         })
     }
 }
+
+/// Builds mesh pipelines once and hands them out by `PipelineKey`. The
+/// shader module is compiled a single time and every pipeline is dropped
+/// when the surface format changes.
+pub struct PipelineCache {
+    module: wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    pipelines: HashMap<PipelineKey, Pipeline>,
+}
+
+impl PipelineCache {
+    pub fn new(
+        device: &Device,
+        shader_label: &str,
+        shader_source: &str,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        Self {
+            module: Pipeline::create_shader(device, shader_label, shader_source),
+            format,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    pub fn set_format(&mut self, format: wgpu::TextureFormat) {
+        if self.format != format {
+            self.format = format;
+            self.pipelines.clear();
+        }
+    }
+
+    /// Creates the pipeline for `key` unless it is already cached.
+    pub fn prepare(
+        &mut self,
+        label: &str,
+        device: &Device,
+        key: &PipelineKey,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+        vertex_layouts: &[wgpu::VertexBufferLayout],
+    ) {
+        if self.pipelines.contains_key(key) {
+            return;
+        }
+        let pipeline = Pipeline::new(
+            label,
+            device,
+            &self.module,
+            key,
+            config,
+            bind_group_layouts,
+            vertex_layouts,
+        );
+        self.pipelines.insert(key.clone(), pipeline);
+    }
+
+    pub fn get(&self, key: &PipelineKey) -> Option<&Pipeline> {
+        self.pipelines.get(key)
+    }
+}
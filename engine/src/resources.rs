@@ -1,14 +1,20 @@
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufReader, Cursor};
+use std::sync::Arc;
 
-use anyhow::Ok;
+use anyhow::{Context, Ok};
 use cfg_if::cfg_if;
+use cgmath::SquareMatrix;
 use wgpu::util::DeviceExt;
 
-use crate::model::Material;
-use crate::utils::{get_relative_path_from_url, is_gltf_file_url, is_url, Vertex};
-use crate::{model, texture};
+use crate::geometry::NormalGeneration;
+use crate::light::{Light, MAX_LIGHTS};
+use crate::model::{Material, MaterialUniform, TextureTransform};
+use crate::utils::{
+    decode_data_uri, get_relative_path_from_url, is_data_uri, is_glb, is_gltf_file_url, is_url,
+    InstanceRaw, Vertex,
+};
+use crate::{draco, environment, geometry, meshopt, model, texture};
 use std::io::Read;
 use url::Url;
 
@@ -18,6 +24,11 @@ fn format_url(path: &str, file_name: &str) -> reqwest::Url {
 }
 
 pub async fn load_string(path: &str) -> anyhow::Result<String> {
+    let data = load_model_binary(path).await?;
+    Ok(String::from_utf8(data)?)
+}
+
+pub async fn load_model_binary(path: &str) -> anyhow::Result<Vec<u8>> {
     cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             let url: reqwest::Url;
@@ -30,18 +41,21 @@ pub async fn load_string(path: &str) -> anyhow::Result<String> {
             } else {
                 url = format_url(path,"scene.gltf");
             }
-            let txt = reqwest::get(url)
+            let data = reqwest::get(url)
                 .await?
-                .text()
-                .await?;
+                .bytes()
+                .await?
+                .to_vec();
         } else {
-            let txt;
+            let data;
             if !is_url(path) {
-                let path = std::path::Path::new(env!("OUT_DIR"))
+                let mut path = std::path::Path::new(env!("OUT_DIR"))
                 .join("models/external")
-                .join(path)
-                .join("scene.gltf");
-                txt = std::fs::read_to_string(path)?;
+                .join(path);
+                if !is_gltf_file_url(path.to_str().unwrap_or_default()) {
+                    path = path.join("scene.gltf");
+                }
+                data = std::fs::read(path)?;
             } else {
                 let url : reqwest::Url;
                 if is_gltf_file_url(path) {
@@ -49,12 +63,12 @@ pub async fn load_string(path: &str) -> anyhow::Result<String> {
                 } else {
                     url = format_url(path,"scene.gltf");
                 }
-                txt = reqwest::blocking::get(url)?.text()?;
+                data = reqwest::blocking::get(url)?.bytes()?.to_vec();
             }
         }
     }
 
-    Ok(txt)
+    Ok(data)
 }
 
 pub async fn load_binary(path: &str, file_name: &str) -> anyhow::Result<Vec<u8>> {
@@ -89,32 +103,753 @@ pub async fn load_binary(path: &str, file_name: &str) -> anyhow::Result<Vec<u8>>
     Ok(data)
 }
 
+/// Loads a buffer or image referenced by a glTF `uri`. Embedded
+/// `data:...;base64,` URIs are decoded in-process, everything else is
+/// fetched relative to `path`.
+pub async fn load_uri(path: &str, uri: &str) -> anyhow::Result<Vec<u8>> {
+    if is_data_uri(uri) {
+        return decode_data_uri(uri);
+    }
+    load_binary(path, uri).await
+}
+
 pub async fn load_texture(
     path: &str,
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    is_albedo_map: bool,
+    color_space: texture::ColorSpace,
 ) -> anyhow::Result<texture::Texture> {
+    let data = load_uri(path, file_name).await?;
+    texture::Texture::from_bytes(device, queue, &data, file_name, color_space)
+}
+
+/// Loads an equirectangular `.hdr` or `.exr` image and bakes it into an
+/// [`Environment`](crate::environment::Environment).
+pub async fn load_environment(
+    path: &str,
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<environment::Environment> {
     let data = load_binary(path, file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name)
+    environment::Environment::from_bytes(device, queue, layout, &data, file_name)
+}
+
+/// Textures already uploaded for a model, keyed by glTF image index and color
+/// space, so materials pointing to the same image share one GPU texture.
+type TextureCache = HashMap<(TextureSource, texture::ColorSpace), Arc<texture::Texture>>;
+
+/// What a cached texture was made from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum TextureSource {
+    /// glTF image index
+    Image(usize),
+    /// Constant color of a missing texture slot, as `f32::to_bits`
+    Factor([u32; 4]),
+}
+
+/// The images a texture can sample, in the order they are tried. Extension
+/// sources (`KHR_texture_basisu` KTX2, then `EXT_texture_webp`) win over the
+/// png/jpeg fallback. The web build has no Basis Universal transcoder and
+/// ignores `KHR_texture_basisu`.
+fn texture_images<'a>(
+    texture: &gltf::Texture<'a>,
+    document: &'a gltf::Document,
+) -> Vec<gltf::Image<'a>> {
+    let extension_image = |name: &str| {
+        texture
+            .extension_value(name)
+            .and_then(|extension| extension.get("source"))
+            .and_then(|source| source.as_u64())
+            .and_then(|index| document.images().nth(index as usize))
+    };
+    let basisu = if cfg!(target_arch = "wasm32") {
+        None
+    } else {
+        extension_image(BASISU_EXTENSION)
+    };
+    let webp = extension_image("EXT_texture_webp");
+    let mut images: Vec<gltf::Image> = Vec::new();
+    for image in [basisu, webp, texture.source()].into_iter().flatten() {
+        if images.iter().all(|other| other.index() != image.index()) {
+            images.push(image);
+        }
+    }
+    images
+}
+
+/// Loads the image behind a glTF texture, either embedded in a buffer view or
+/// referenced by uri. Images already in the loader's cache are reused. An image that fails
+/// to decode falls back to the next one the texture lists.
+async fn load_gltf_texture(
+    texture: &gltf::Texture<'_>,
+    document: &gltf::Document,
+    color_space: texture::ColorSpace,
+    loader: &mut MaterialLoader<'_>,
+) -> anyhow::Result<Arc<texture::Texture>> {
+    let MaterialLoader {
+        device,
+        queue,
+        url,
+        buffer_data,
+        ..
+    } = *loader;
+    let mut error = anyhow::anyhow!("Texture {} has no image", texture.index());
+    for image in texture_images(texture, document) {
+        if let Some(texture) = loader
+            .textures
+            .get(&(TextureSource::Image(image.index()), color_space))
+        {
+            return Ok(texture.clone());
+        }
+        let loaded = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &buffer_data[view.buffer().index()];
+                texture::Texture::from_bytes(
+                    device,
+                    queue,
+                    &buffer[view.offset()..view.offset() + view.length()],
+                    url,
+                    color_space,
+                )
+            }
+            gltf::image::Source::Uri { uri, .. } => {
+                load_texture(url, uri, device, queue, color_space).await
+            }
+        };
+        match loaded {
+            Result::Ok(loaded) => {
+                let loaded = Arc::new(loaded);
+                loader.textures.insert(
+                    (TextureSource::Image(image.index()), color_space),
+                    loaded.clone(),
+                );
+                return Ok(loaded);
+            }
+            Err(e) => {
+                log::warn!(
+                    "Texture {} can't use image {}: {e:#}",
+                    texture.index(),
+                    image.index()
+                );
+                error = e;
+            }
+        }
+    }
+    Err(error)
+}
+
+/// What material loading reads textures with: the device, the model's base
+/// url and buffers, and the samplers and textures shared by its materials.
+struct MaterialLoader<'a> {
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    url: &'a str,
+    buffer_data: &'a [Vec<u8>],
+    samplers: texture::SamplerCache,
+    textures: TextureCache,
+}
+
+/// A 1x1 texture of `factor` for a slot the material leaves empty, shared by
+/// all materials of the model.
+fn factor_texture(
+    factor: [f32; 4],
+    color_space: texture::ColorSpace,
+    loader: &mut MaterialLoader<'_>,
+) -> anyhow::Result<Arc<texture::Texture>> {
+    let key = (TextureSource::Factor(factor.map(f32::to_bits)), color_space);
+    if let Some(texture) = loader.textures.get(&key) {
+        return Ok(texture.clone());
+    }
+    let texture = Arc::new(texture::Texture::from_factor(
+        loader.device,
+        loader.queue,
+        &factor,
+        loader.url,
+        color_space,
+    )?);
+    loader.textures.insert(key, texture.clone());
+    Ok(texture)
+}
+
+async fn load_material(
+    material: &gltf::Material<'_>,
+    document: &gltf::Document,
+    index: usize,
+    texture_bind_group_layout: &Arc<wgpu::BindGroupLayout>,
+    loader: &mut MaterialLoader<'_>,
+) -> anyhow::Result<Material> {
+    let device = loader.device;
+    println!(
+        "Loading material: {}",
+        material.name().unwrap_or("Undefine").to_string()
+    );
+    let name = material.name().unwrap_or("Default Material").to_string();
+    let pbr: gltf::material::PbrMetallicRoughness<'_> = material.pbr_metallic_roughness();
+
+    // Missing textures are replaced by white so the factors apply unchanged
+    let diffuse_texture = match pbr.base_color_texture() {
+        Some(texture_info) => {
+            load_gltf_texture(
+                &texture_info.texture(),
+                document,
+                texture::ColorSpace::Srgb,
+                loader,
+            )
+            .await?
+        }
+        None => {
+            println!("This material doesn't have diffuse(albedo) texture. Creating one");
+            factor_texture([1.0; 4], texture::ColorSpace::Srgb, loader)?
+        }
+    };
+    let metallic_roughness_texture = match pbr.metallic_roughness_texture() {
+        Some(texture_info) => {
+            load_gltf_texture(
+                &texture_info.texture(),
+                document,
+                texture::ColorSpace::Linear,
+                loader,
+            )
+            .await?
+        }
+        None => factor_texture([1.0; 4], texture::ColorSpace::Linear, loader)?,
+    };
+
+    let normal_texture = match material.normal_texture() {
+        Some(normal_texture) => {
+            load_gltf_texture(
+                &normal_texture.texture(),
+                document,
+                texture::ColorSpace::Linear,
+                loader,
+            )
+            .await?
+        }
+        None => factor_texture([0.5, 0.5, 1.0, 1.0], texture::ColorSpace::Linear, loader)?,
+    };
+    let emissive_texture = match material.emissive_texture() {
+        Some(texture_info) => {
+            load_gltf_texture(
+                &texture_info.texture(),
+                document,
+                texture::ColorSpace::Srgb,
+                loader,
+            )
+            .await?
+        }
+        None => factor_texture([1.0; 4], texture::ColorSpace::Srgb, loader)?,
+    };
+    let occlusion_texture = match material.occlusion_texture() {
+        Some(occlusion_texture) => {
+            load_gltf_texture(
+                &occlusion_texture.texture(),
+                document,
+                texture::ColorSpace::Linear,
+                loader,
+            )
+            .await?
+        }
+        None => factor_texture([1.0; 4], texture::ColorSpace::Linear, loader)?,
+    };
+
+    let mut sampler = |texture: Option<gltf::Texture<'_>>| {
+        let key = texture.map_or_else(Default::default, |texture| sampler_key(&texture.sampler()));
+        loader.samplers.get(device, key)
+    };
+    let diffuse_sampler = sampler(pbr.base_color_texture().map(|t| t.texture()));
+    let metallic_roughness_sampler = sampler(pbr.metallic_roughness_texture().map(|t| t.texture()));
+    let normal_sampler = sampler(material.normal_texture().map(|t| t.texture()));
+    let emissive_sampler = sampler(material.emissive_texture().map(|t| t.texture()));
+    let occlusion_sampler = sampler(material.occlusion_texture().map(|t| t.texture()));
+
+    // Normal and occlusion textures have their own info types, all of them
+    // carry the UV set and KHR_texture_transform the same way
+    macro_rules! texture_transform {
+        ($info:expr) => {
+            $info.map_or_else(Default::default, |info| {
+                TextureTransform::new(
+                    info.tex_coord(),
+                    info.extension_value(TextureTransform::EXTENSION),
+                )
+            })
+        };
+    }
+
+    let alpha_mode = material.alpha_mode();
+    let mut uniform = MaterialUniform {
+        base_color_factor: pbr.base_color_factor(),
+        metallic_factor: pbr.metallic_factor(),
+        roughness_factor: pbr.roughness_factor(),
+        normal_scale: material.normal_texture().map_or(1.0, |t| t.scale()),
+        has_normal_texture: material.normal_texture().is_some() as u32,
+        alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+        occlusion_strength: material.occlusion_texture().map_or(1.0, |t| t.strength()),
+        emissive_factor: material.emissive_factor(),
+        emissive_strength: material.emissive_strength().unwrap_or(1.0),
+        base_color_transform: texture_transform!(pbr.base_color_texture()),
+        metallic_roughness_transform: texture_transform!(pbr.metallic_roughness_texture()),
+        normal_transform: texture_transform!(material.normal_texture()),
+        occlusion_transform: texture_transform!(material.occlusion_texture()),
+        emissive_transform: texture_transform!(material.emissive_texture()),
+        ..Default::default()
+    };
+    uniform.set_alpha_mode(alpha_mode);
+    let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{name} Material Buffer")),
+        contents: bytemuck::cast_slice(&[uniform]),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: texture_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&diffuse_sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&metallic_roughness_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Sampler(&metallic_roughness_sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::Sampler(&normal_sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: wgpu::BindingResource::Sampler(&emissive_sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 9,
+                resource: wgpu::BindingResource::TextureView(&occlusion_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 10,
+                resource: wgpu::BindingResource::Sampler(&occlusion_sampler),
+            },
+        ],
+        label: Some(&(name.clone() + "text_bind_group")),
+    });
+
+    Ok(Material {
+        name,
+        index,
+        bind_group,
+        texture_bind_group_layout: texture_bind_group_layout.clone(),
+        uniform,
+        uniform_buffer,
+        alpha_mode,
+        double_sided: material.double_sided(),
+    })
+}
+
+/// Maps glTF wrap and filter modes to wgpu. Filters the asset leaves undefined
+/// default to linear with mipmaps.
+fn sampler_key(sampler: &gltf::texture::Sampler<'_>) -> texture::SamplerKey {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    };
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => wgpu::FilterMode::Nearest,
+        Some(MagFilter::Linear) | None => wgpu::FilterMode::Linear,
+    };
+    let (min_filter, mipmap_filter, mipmaps) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest, false),
+        Some(MinFilter::Linear) => (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest, false),
+        Some(MinFilter::NearestMipmapNearest) => {
+            (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest, true)
+        }
+        Some(MinFilter::LinearMipmapNearest) => {
+            (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest, true)
+        }
+        Some(MinFilter::NearestMipmapLinear) => {
+            (wgpu::FilterMode::Nearest, wgpu::FilterMode::Linear, true)
+        }
+        Some(MinFilter::LinearMipmapLinear) | None => {
+            (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, true)
+        }
+    };
+    texture::SamplerKey {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        mag_filter,
+        min_filter,
+        mipmap_filter,
+        mipmaps,
+    }
+}
+
+/// Walks the node tree depth first, composing each node's local TRS or
+/// matrix transform with its parent's world transform. Lights attached to
+/// nodes are collected along the way.
+fn load_node(
+    node: &gltf::Node,
+    parent: &cgmath::Matrix4<f32>,
+    nodes: &mut Vec<model::Node>,
+    lights: &mut Vec<Light>,
+) {
+    let transform = parent * cgmath::Matrix4::from(node.transform().matrix());
+    let name = node.name().unwrap_or("Unknown").to_string();
+    let mesh = node.mesh().map(|mesh| mesh.index());
+    log::debug!("Loading node: {name} [{}]", mesh.unwrap_or(usize::MAX));
+
+    if let Some(light) = node.light() {
+        log::info!("Loading light: {}", light.name().unwrap_or("Unknown"));
+        lights.push(Light::from_gltf(&light, &transform));
+    }
+    for child in node.children() {
+        load_node(&child, &transform, nodes, lights);
+    }
+    nodes.push(model::Node {
+        name,
+        mesh,
+        transform,
+    });
+}
+
+/// Vertex and index data of one primitive, before normals and tangents are
+/// generated for it.
+pub struct PrimitiveData {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub has_normals: bool,
+    pub has_tex_coords: bool,
+    pub has_tangents: bool,
+}
+
+/// Reads an uncompressed primitive through its accessors.
+fn read_primitive(primitive: &gltf::Primitive, buffer_data: &[Vec<u8>]) -> PrimitiveData {
+    let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
+
+    // Quantized attributes (KHR_mesh_quantization) are read as floats too
+    let attribute = |semantic| primitive.get(&semantic);
+    let positions = attribute(gltf::Semantic::Positions)
+        .and_then(|accessor| read_accessor::<3>(&accessor, buffer_data));
+    let normals = attribute(gltf::Semantic::Normals)
+        .and_then(|accessor| read_accessor::<3>(&accessor, buffer_data));
+    let tex_coords = attribute(gltf::Semantic::TexCoords(0))
+        .and_then(|accessor| read_accessor::<2>(&accessor, buffer_data));
+    let tex_coords_1 = attribute(gltf::Semantic::TexCoords(1))
+        .and_then(|accessor| read_accessor::<2>(&accessor, buffer_data));
+    let tangents = attribute(gltf::Semantic::Tangents)
+        .and_then(|accessor| read_accessor::<4>(&accessor, buffer_data));
+
+    let mut vertices = Vec::new();
+    if let Some(vertex_attribute) = &positions {
+        vertex_attribute.iter().for_each(|&vertex| {
+            // dbg!(vertex);
+            vertices.push(Vertex {
+                position: vertex,
+                color: [1.0; 4],
+                tex_coords: Default::default(),
+                normal: Default::default(),
+                tangent: Default::default(),
+                tex_coords_1: Default::default(),
+            })
+        });
+    }
+
+    if let Some(normal_attribute) = &normals {
+        let mut normal_index = 0;
+        normal_attribute.iter().for_each(|&normal| {
+            vertices[normal_index].normal = normal;
+            normal_index += 1;
+        });
+    }
+
+    if let Some(tex_coord_attribute) = &tex_coords {
+        let mut tex_coord_index = 0;
+        tex_coord_attribute.iter().for_each(|tex_coord| {
+            // dbg!(tex_coord);
+            vertices[tex_coord_index].tex_coords = [tex_coord[0], tex_coord[1]];
+
+            tex_coord_index += 1;
+        });
+    }
+
+    if let Some(tex_coord_attribute) = &tex_coords_1 {
+        for (vertex, &tex_coord) in vertices.iter_mut().zip(tex_coord_attribute) {
+            vertex.tex_coords_1 = tex_coord;
+        }
+    }
+
+    if let Some(color_attribute) = reader.read_colors(0) {
+        let mut color_index = 0;
+        color_attribute.into_rgba_f32().for_each(|color| {
+            vertices[color_index].color = color;
+            color_index += 1;
+        });
+    }
+
+    if let Some(tangent_attribute) = &tangents {
+        let mut tangent_index = 0;
+        tangent_attribute.iter().for_each(|&tangent| {
+            vertices[tangent_index].tangent = tangent;
+            tangent_index += 1;
+        });
+    }
+
+    let mut indices = Vec::new();
+    if let Some(indices_raw) = reader.read_indices() {
+        // dbg!(indices_raw);
+        indices.append(&mut indices_raw.into_u32().collect::<Vec<u32>>());
+    }
+
+    PrimitiveData {
+        vertices,
+        indices,
+        has_normals: normals.is_some(),
+        has_tex_coords: tex_coords.is_some(),
+        has_tangents: tangents.is_some(),
+    }
+}
+
+/// The topology a primitive is drawn with. Modes wgpu has no topology for are
+/// rewritten into `indices`, and triangle strips become lists as well since
+/// normal and tangent generation and the shadow pass expect lists.
+fn primitive_topology(
+    mode: gltf::mesh::Mode,
+    vertices: &[Vertex],
+    indices: &mut Vec<u32>,
+) -> wgpu::PrimitiveTopology {
+    use gltf::mesh::Mode;
+    match mode {
+        Mode::Points => wgpu::PrimitiveTopology::PointList,
+        Mode::Lines => wgpu::PrimitiveTopology::LineList,
+        Mode::LineStrip => wgpu::PrimitiveTopology::LineStrip,
+        Mode::LineLoop => {
+            *indices = geometry::loop_to_strip(vertices, indices);
+            wgpu::PrimitiveTopology::LineStrip
+        }
+        Mode::Triangles => wgpu::PrimitiveTopology::TriangleList,
+        Mode::TriangleStrip => {
+            *indices = geometry::strip_to_list(vertices, indices);
+            wgpu::PrimitiveTopology::TriangleList
+        }
+        Mode::TriangleFan => {
+            *indices = geometry::fan_to_list(vertices, indices);
+            wgpu::PrimitiveTopology::TriangleList
+        }
+    }
+}
+
+/// Reads a float vector accessor. Integer components are dequantized, scaled
+/// to [0, 1] or [-1, 1] when the accessor is normalized.
+fn read_accessor<const N: usize>(
+    accessor: &gltf::Accessor,
+    buffer_data: &[Vec<u8>],
+) -> Option<Vec<[f32; N]>>
+where
+    [f32; N]: gltf::accessor::Item,
+    [i8; N]: gltf::accessor::Item,
+    [u8; N]: gltf::accessor::Item,
+    [i16; N]: gltf::accessor::Item,
+    [u16; N]: gltf::accessor::Item,
+{
+    use gltf::accessor::DataType;
+
+    match accessor.data_type() {
+        DataType::F32 => read_components::<f32, N>(accessor, buffer_data),
+        DataType::I8 => read_components::<i8, N>(accessor, buffer_data),
+        DataType::U8 => read_components::<u8, N>(accessor, buffer_data),
+        DataType::I16 => read_components::<i16, N>(accessor, buffer_data),
+        DataType::U16 => read_components::<u16, N>(accessor, buffer_data),
+        DataType::U32 => None,
+    }
+}
+
+fn read_components<T, const N: usize>(
+    accessor: &gltf::Accessor,
+    buffer_data: &[Vec<u8>],
+) -> Option<Vec<[f32; N]>>
+where
+    T: Copy + Into<f32>,
+    [T; N]: gltf::accessor::Item,
+{
+    let data_type = accessor.data_type();
+    let normalized = accessor.normalized();
+    let iter = gltf::accessor::Iter::<[T; N]>::new(accessor.clone(), |buffer: gltf::Buffer| {
+        buffer_data.get(buffer.index()).map(Vec::as_slice)
+    })?;
+    Some(
+        iter.map(|value| {
+            value.map(|component| dequantize(component.into(), data_type, normalized))
+        })
+        .collect(),
+    )
+}
+
+/// Maps a normalized integer component to [0, 1] or [-1, 1], other values
+/// are used as they are.
+pub fn dequantize(value: f32, data_type: gltf::accessor::DataType, normalized: bool) -> f32 {
+    if normalized {
+        (value / normalized_max(data_type)).max(-1.0)
+    } else {
+        value
+    }
+}
+
+/// The integer that a normalized component maps to 1.
+fn normalized_max(data_type: gltf::accessor::DataType) -> f32 {
+    use gltf::accessor::DataType;
+
+    match data_type {
+        DataType::I8 => i8::MAX as f32,
+        DataType::U8 => u8::MAX as f32,
+        DataType::I16 => i16::MAX as f32,
+        DataType::U16 => u16::MAX as f32,
+        DataType::U32 => u32::MAX as f32,
+        DataType::F32 => 1.0,
+    }
+}
+
+/// Local bounds from the POSITION accessor's min and max, which quantized
+/// positions store in integer units.
+fn position_bounds(primitive: &gltf::Primitive) -> geometry::Aabb {
+    let bounding_box = primitive.bounding_box();
+    let scale = match primitive.get(&gltf::Semantic::Positions) {
+        Some(accessor) if accessor.normalized() => normalized_max(accessor.data_type()),
+        _ => 1.0,
+    };
+    geometry::Aabb {
+        min: cgmath::Point3::from(bounding_box.min) / scale,
+        max: cgmath::Point3::from(bounding_box.max) / scale,
+    }
+}
+
+/// Parses either a text `.gltf` or a binary `.glb` container. For GLB the
+/// JSON chunk becomes the document and the BIN chunk becomes the blob that
+/// `gltf::buffer::Source::Bin` refers to.
+fn parse_gltf(data: &[u8]) -> anyhow::Result<gltf::Gltf> {
+    if !is_glb(data) {
+        let root = gltf::json::Root::from_slice(data)?;
+        return Ok(gltf::Gltf {
+            document: document_from_json(root)?,
+            blob: None,
+        });
+    }
+    let glb = gltf::Glb::from_slice(data)?;
+    log::debug!("Found a glb container, version {}", glb.header.version);
+    let root = gltf::json::Root::from_slice(&glb.json)?;
+    Ok(gltf::Gltf {
+        document: document_from_json(root)?,
+        blob: glb.bin.map(|bin| bin.into_owned()),
+    })
+}
+
+/// Extensions the loader implements itself, the gltf crate rejects files
+/// that require them.
+const LOADER_EXTENSIONS: &[&str] = &[
+    draco::EXTENSION,
+    meshopt::EXTENSION,
+    "KHR_mesh_quantization",
+    TextureTransform::EXTENSION,
+];
+
+/// KTX2 textures with Basis Universal payloads. The transcoder is C++, so the
+/// web build can't load files that require it.
+const BASISU_EXTENSION: &str = "KHR_texture_basisu";
+
+/// Validates like `gltf::Document::from_json`, but accepts the extensions in
+/// `LOADER_EXTENSIONS` and the Draco accessors without a bufferView.
+fn document_from_json(root: gltf::json::Root) -> anyhow::Result<gltf::Document> {
+    use gltf::json::validation::{Error, Validate};
+
+    if cfg!(target_arch = "wasm32")
+        && root
+            .extensions_required
+            .iter()
+            .any(|extension| extension == BASISU_EXTENSION)
+    {
+        return Err(anyhow::anyhow!(
+            "{BASISU_EXTENSION} is required, but the web build can't transcode KTX2 textures"
+        ));
+    }
+
+    let mut errors = Vec::new();
+    root.validate(&root, gltf::json::Path::new, &mut |path, error| {
+        errors.push((path(), error))
+    });
+    let draco = root
+        .extensions_used
+        .iter()
+        .any(|extension| extension == draco::EXTENSION);
+    errors.retain(|(path, error)| match error {
+        Error::Unsupported => !LOADER_EXTENSIONS
+            .iter()
+            .any(|extension| path.as_str().ends_with(&format!("\"{extension}\""))),
+        // Draco accessors have their data in the compressed stream instead
+        Error::Missing => {
+            !(draco
+                && path.as_str().starts_with("accessors[")
+                && path.as_str().ends_with(".bufferView"))
+        }
+        _ => true,
+    });
+    if !errors.is_empty() {
+        return Err(gltf::Error::Validation(errors).into());
+    }
+    Ok(gltf::Document::from_json_without_validation(root))
+}
+
+/// Knobs for `load_model_with_options`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModelLoadOptions {
+    pub normal_generation: NormalGeneration,
 }
 
 pub async fn load_model(
     path: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+) -> anyhow::Result<model::Model> {
+    load_model_with_options(path, device, queue, &ModelLoadOptions::default()).await
+}
+
+pub async fn load_model_with_options(
+    path: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    options: &ModelLoadOptions,
 ) -> anyhow::Result<model::Model> {
     let url = get_relative_path_from_url(path);
-    let obj_text = load_string(&path).await?;
-    let obj_cursor: Cursor<String> = Cursor::new(obj_text);
-    let obj_reader = BufReader::new(obj_cursor);
-    let gltf = gltf::Gltf::from_reader(obj_reader)?;
+    let obj_data = load_model_binary(&path).await?;
+    let gltf = parse_gltf(&obj_data)?;
 
     // Load buffers
     let mut buffer_data = Vec::new();
     for buffer in gltf.buffers() {
         match buffer.source() {
+            // Filled in when its compressed bufferViews are decoded
+            _ if meshopt::is_fallback_buffer(&buffer) => {
+                buffer_data.push(vec![0; buffer.length()]);
+            }
             gltf::buffer::Source::Bin => {
                 if let Some(blob) = gltf.blob.as_deref() {
                     buffer_data.push(blob.into());
@@ -122,114 +857,112 @@ pub async fn load_model(
                 };
             }
             gltf::buffer::Source::Uri(uri) => {
-                let bin = load_binary(url, uri).await?;
+                let bin = load_uri(url, uri).await?;
                 buffer_data.push(bin);
             }
         }
     }
+    meshopt::decode_buffer_views(&gltf, &mut buffer_data)?;
 
     // Load materials
     let mut materials = Vec::new();
+    let texture_bind_group_layout = Arc::new(Material::create_bind_group_layout(device));
+    let mut loader = MaterialLoader {
+        device,
+        queue,
+        url,
+        buffer_data: &buffer_data,
+        samplers: texture::SamplerCache::new(),
+        textures: TextureCache::new(),
+    };
     for material in gltf.materials() {
-        println!(
-            "Loading material: {}",
-            material.name().unwrap_or("Undefine").to_string()
-        );
-        let name = material.name().unwrap_or("Default Material").to_string();
-        let pbr: gltf::material::PbrMetallicRoughness<'_> = material.pbr_metallic_roughness();
-        let diffuse_factor = pbr.base_color_factor();
-        let diffuse_texture: Option<texture::Texture> = match pbr.base_color_texture() {
-            Some(texture_info) => {
-                match texture_info.texture().source().source() {
-                    gltf::image::Source::View { view, .. } => {
-                        // Load normal texture from buffer view
-                        let texture_source = texture::Texture::from_bytes(
-                            device,
-                            queue,
-                            &buffer_data[view.buffer().index()],
-                            url,
-                        )
-                        .expect("Couldn't load normal texture");
-                        Some(texture_source)
-                    }
-                    gltf::image::Source::Uri { uri, .. } => {
-                        // Load normal texture from URI
-                        let texture = load_texture(url, uri, device, queue, true)
-                            .await
-                            .expect("Couldn't load normal texture");
-                        Some(texture)
-                    }
-                }
-            }
-            None => {
-                let default_diffuse_texture =
-                    crate::texture::Texture::from_factor(device, queue, &diffuse_factor, url)
-                        .expect("Couldn't load normal texture");
-                println!("This material doesn't have diffuse(albedo) texture. Creating one");
-                Some(default_diffuse_texture)
-            }
-        };
-       
-
-        let diffuse_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Diffuse Factor Buffer"),
-            contents: bytemuck::cast_slice(&[diffuse_factor]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-
-        let mut bind_group_entries: Vec<wgpu::BindGroupEntry<'_>> = vec![];
-        let mut bind_group_layout_entries: Vec<wgpu::BindGroupLayoutEntry> = vec![];
+        let material = load_material(
+            &material,
+            &gltf,
+            materials.len(),
+            &texture_bind_group_layout,
+            &mut loader,
+        )
+        .await?;
+        materials.push(material);
+    }
+    // Primitives without a material use the glTF default material, appended last
+    let default_material = materials.len();
+    if let Some(primitive) = gltf
+        .meshes()
+        .flat_map(|mesh| mesh.primitives())
+        .find(|primitive| primitive.material().index().is_none())
+    {
+        let material = load_material(
+            &primitive.material(),
+            &gltf,
+            default_material,
+            &texture_bind_group_layout,
+            &mut loader,
+        )
+        .await?;
+        materials.push(material);
+    }
 
-        if let Some(diffuse_texture) = &diffuse_texture {
-            bind_group_entries.push(wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-            });
-            bind_group_entries.push(wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-            });
-            bind_group_layout_entries.push(wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    multisampled: false,
-                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    view_dimension: wgpu::TextureViewDimension::D2,
-                },
-                count: None,
-            });
-            bind_group_layout_entries.push(wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                count: None,
-            });
+    // Scene graph
+    let mut nodes = Vec::new();
+    let mut lights = Vec::new();
+    let scene_roots: Vec<gltf::Node> = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => scene.nodes().collect(),
+        None => {
+            log::debug!("This file doesn't have a scene, drawing every root node");
+            let children: Vec<usize> = gltf
+                .nodes()
+                .flat_map(|node| node.children().map(|child| child.index()))
+                .collect();
+            gltf.nodes()
+                .filter(|node| !children.contains(&node.index()))
+                .collect()
         }
+    };
+    for node in scene_roots {
+        load_node(&node, &cgmath::Matrix4::identity(), &mut nodes, &mut lights);
+    }
+    if lights.len() > MAX_LIGHTS {
+        log::warn!(
+            "Only the first {MAX_LIGHTS} of {} lights are used",
+            lights.len()
+        );
+    }
 
-        // texture_bind_group_layout
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some(
-                    &(material.name().unwrap_or("Default Material").to_string()
-                        + "text_bind_group_layout"),
-                ),
-                entries: &bind_group_layout_entries,
-            });
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
-            entries: &bind_group_entries,
-            label: Some(
-                &(material.name().unwrap_or("Default Material").to_string() + "text_bind_group"),
-            ),
+    // Group the world transforms by mesh so a mesh shared by several nodes is drawn instanced.
+    // Mirrored transforms come last in their own range, they are drawn with clockwise front faces
+    let mut instances = Vec::new();
+    let mut mesh_instances = Vec::new();
+    for mesh in gltf.meshes() {
+        let ranges = [false, true].map(|mirrored| {
+            let start = instances.len() as u32;
+            nodes
+                .iter()
+                .filter(|node| node.mesh == Some(mesh.index()))
+                .filter(|node| (node.transform.determinant() < 0.0) == mirrored)
+                .for_each(|node| instances.push(node.transform));
+            start..instances.len() as u32
         });
+        mesh_instances.push(ranges);
+    }
+    let instance_data = instances
+        .iter()
+        .map(InstanceRaw::from_matrix)
+        .collect::<Vec<_>>();
 
-        materials.push(Material {
-            name,
-            bind_group,
-            texture_bind_group_layout,
-            index: materials.len(),
-        });
+    // World space bounds of everything drawn, from the accessor min/max
+    let mut bounds = geometry::Aabb::empty();
+    for node in &nodes {
+        let Some(mesh) = node.mesh.and_then(|index| gltf.meshes().nth(index)) else {
+            continue;
+        };
+        for primitive in mesh.primitives() {
+            let local = position_bounds(&primitive);
+            let world = local.transform(&node.transform);
+            bounds.extend(world.min);
+            bounds.extend(world.max);
+        }
     }
 
     let mut meshes = Vec::new();
@@ -247,61 +980,70 @@ pub async fn load_model(
             }
         };
 
-        let primitives = mesh.primitives();
-        primitives.for_each(|primitive| {
-            // dbg!(primitive);
-
-            let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
-
-            let mut vertices = Vec::new();
-            if let Some(vertex_attribute) = reader.read_positions() {
-                vertex_attribute.for_each(|vertex| {
-                    // dbg!(vertex);
-                    vertices.push(Vertex {
-                        position: vertex,
-                        color: Default::default(),
-                        tex_coords: Default::default(),
-                        normal: Default::default(),
-                    })
-                });
-            }
-
-            if let Some(tex_coord_attribute) = reader.read_tex_coords(0).map(|v| v.into_f32()) {
-                let mut tex_coord_index = 0;
-                tex_coord_attribute.for_each(|tex_coord| {
-                    // dbg!(tex_coord);
-                    vertices[tex_coord_index].tex_coords = [tex_coord[0], tex_coord[1]];
+        for primitive in mesh.primitives() {
+            let decoded = draco::decode_primitive(&primitive, &gltf, &buffer_data)
+                .with_context(|| format!("{mesh_name}: can't decode the Draco primitive"))?;
+            let PrimitiveData {
+                mut vertices,
+                mut indices,
+                mut has_normals,
+                has_tex_coords,
+                has_tangents,
+            } = match decoded {
+                Some(decoded) => decoded,
+                None => read_primitive(&primitive, &buffer_data),
+            };
 
-                    tex_coord_index += 1;
-                });
-            }
+            let topology = primitive_topology(primitive.mode(), &vertices, &mut indices);
+            let triangles = topology == wgpu::PrimitiveTopology::TriangleList;
 
-            if let Some(color_attribute) = reader.read_colors(0) {
-                let mut color_index = 0;
-                color_attribute.into_rgba_f32().for_each(|color| {
-                    vertices[color_index].color = [color[0], color[1], color[2]];
-                    color_index += 1;
-                });
+            if !has_normals && triangles {
+                log::debug!("This primitive doesn't have normals. Generating them");
+                match options.normal_generation {
+                    NormalGeneration::Flat => {
+                        geometry::generate_flat_normals(&mut vertices, &mut indices)
+                    }
+                    NormalGeneration::Smooth => {
+                        geometry::generate_smooth_normals(&mut vertices, &indices)
+                    }
+                }
+                has_normals = true;
             }
-
-            let mut indices = Vec::new();
-            if let Some(indices_raw) = reader.read_indices() {
-                // dbg!(indices_raw);
-                indices.append(&mut indices_raw.into_u32().collect::<Vec<u32>>());
+            if !has_tangents
+                && triangles
+                && primitive.material().normal_texture().is_some()
+                && has_normals
+                && has_tex_coords
+            {
+                log::debug!("This primitive doesn't have tangents. Generating them");
+                if !geometry::generate_tangents(&mut vertices, &indices) {
+                    log::warn!("Couldn't generate tangents");
+                }
             }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path)),
-                contents: bytemuck::cast_slice(&indices),
-                usage: wgpu::BufferUsages::INDEX,
+            let vertex_buffer = Arc::new(device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{:?} Vertex Buffer", path)),
+                    contents: bytemuck::cast_slice(&vertices),
+                    usage: wgpu::BufferUsages::VERTEX,
+                },
+            ));
+            // Unindexed primitives draw their vertices in order
+            let index_buffer = (!indices.is_empty()).then(|| {
+                Arc::new(
+                    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some(&format!("{:?} Index Buffer", path)),
+                        contents: bytemuck::cast_slice(&indices),
+                        usage: wgpu::BufferUsages::INDEX,
+                    }),
+                )
             });
-            // From 1->n+1, Zero if this mesh doesn't use mat
-            let mut material = 0;
+            let num_elements = if indices.is_empty() {
+                vertices.len()
+            } else {
+                indices.len()
+            };
+            let mut material = default_material;
             match primitive.material().index() {
                 Some(_mat) => material = _mat,
                 None => {
@@ -311,42 +1053,43 @@ pub async fn load_model(
 
             let mesh_index = primitive.index(); // Test index
 
-            meshes.push(model::Mesh {
-                name: mesh_name.to_string(),
-                index: mesh_index,
-                vertex_buffer,
-                index_buffer,
-                num_elements: indices.len() as u32,
-                material: material as usize,
-            });
-        });
-    }
-
-    for node in gltf.nodes() {
-        // Access rotation and translation if available
-        let transform = node.transform();
-        let mut node_name;
-        let mut mesh_index;
-        match node.mesh() {
-            Some(mesh) => {
-                mesh_index = mesh.index();
-            }
-            None => mesh_index = usize::MAX,
-        };
-        match node.name() {
-            Some(name) => {
-                node_name = name;
-                println!("Loading node: {name} [{}]", mesh_index);
-            }
-            None => {
-                println!("Loading node: Unknown [{}]", mesh_index);
-                node_name = "Unknown"
+            // A second mesh draws the mirrored instances with the same buffers
+            let [instances, mirrored_instances] = mesh_instances[mesh.index()].clone();
+            for (instances, mirrored) in [(instances, false), (mirrored_instances, true)] {
+                if mirrored && instances.is_empty() {
+                    continue;
+                }
+                meshes.push(model::Mesh {
+                    name: mesh_name.to_string(),
+                    index: mesh_index,
+                    vertex_buffer: vertex_buffer.clone(),
+                    index_buffer: index_buffer.clone(),
+                    num_elements: num_elements as u32,
+                    topology,
+                    material: material as usize,
+                    bounds: position_bounds(&primitive),
+                    instances,
+                    mirrored,
+                    unlit: !has_normals,
+                });
             }
-        };   
+        }
     }
-    
+
+    let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{:?} Instance Buffer", path)),
+        contents: bytemuck::cast_slice(&instance_data),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+
     Ok(model::Model {
         meshes,
         materials,
+        material_layout: texture_bind_group_layout,
+        nodes,
+        instance_buffer,
+        lights,
+        bounds,
+        instances,
     })
 }
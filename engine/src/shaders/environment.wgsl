@@ -0,0 +1,209 @@
+// Offline passes that turn an equirectangular HDR image into the image
+// based lighting inputs: environment cubemap, irradiance cubemap,
+// prefiltered specular cubemap and the BRDF lookup table.
+
+const PI: f32 = 3.14159265359;
+
+struct BakeUniform {
+    // Cube face being rendered, +X -X +Y -Y +Z -Z
+    face: u32,
+    // Roughness of the prefiltered mip level
+    roughness: f32,
+    // Mip level of `t_source` to read from
+    source_mip: f32,
+    // Texel count along one edge of `t_source` mip 0
+    source_size: f32,
+};
+@group(0) @binding(0)
+var<uniform> bake: BakeUniform;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// Fullscreen triangle, uv covers [-1, 1] over the target with +y up
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let x = f32((vertex_index << 1u) & 2u);
+    let y = f32(vertex_index & 2u);
+    var out: VertexOutput;
+    out.uv = vec2<f32>(x * 2.0 - 1.0, y * 2.0 - 1.0);
+    out.clip_position = vec4<f32>(x * 2.0 - 1.0, y * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+// Direction through a texel of a cube face, following the cubemap convention
+fn cube_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    switch face {
+        case 0u: { return normalize(vec3<f32>(1.0, uv.y, -uv.x)); }
+        case 1u: { return normalize(vec3<f32>(-1.0, uv.y, uv.x)); }
+        case 2u: { return normalize(vec3<f32>(uv.x, 1.0, -uv.y)); }
+        case 3u: { return normalize(vec3<f32>(uv.x, -1.0, uv.y)); }
+        case 4u: { return normalize(vec3<f32>(uv.x, uv.y, 1.0)); }
+        default: { return normalize(vec3<f32>(-uv.x, uv.y, -1.0)); }
+    }
+}
+
+// Equirectangular to cubemap. The source is Rgba32Float, which is not
+// filterable everywhere, so the bilinear filter is done by hand.
+@group(1) @binding(0)
+var t_equirect: texture_2d<f32>;
+
+fn load_equirect(texel: vec2<i32>, size: vec2<i32>) -> vec4<f32> {
+    let wrapped = vec2<i32>((texel.x + size.x) % size.x, clamp(texel.y, 0, size.y - 1));
+    return textureLoad(t_equirect, wrapped, 0);
+}
+
+@fragment
+fn fs_equirect(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = cube_direction(bake.face, in.uv);
+    let uv = vec2<f32>(atan2(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+    let size = vec2<i32>(textureDimensions(t_equirect));
+    let position = uv * vec2<f32>(size) - 0.5;
+    let base = vec2<i32>(floor(position));
+    let f = fract(position);
+    let top = mix(load_equirect(base, size), load_equirect(base + vec2<i32>(1, 0), size), f.x);
+    let bottom = mix(
+        load_equirect(base + vec2<i32>(0, 1), size),
+        load_equirect(base + vec2<i32>(1, 1), size),
+        f.x,
+    );
+    return vec4<f32>(mix(top, bottom, f.y).rgb, 1.0);
+}
+
+@group(1) @binding(1)
+var t_source: texture_cube<f32>;
+@group(1) @binding(2)
+var s_source: sampler;
+
+// Box filter into the next mip level of the environment cubemap
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = cube_direction(bake.face, in.uv);
+    return vec4<f32>(textureSampleLevel(t_source, s_source, dir, bake.source_mip).rgb, 1.0);
+}
+
+fn tangent_basis(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(n.y) > 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let right = normalize(cross(up, n));
+    return mat3x3<f32>(right, cross(n, right), n);
+}
+
+// Cosine weighted convolution of the environment over the hemisphere
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n = cube_direction(bake.face, in.uv);
+    let basis = tangent_basis(n);
+    let delta = 0.05;
+    var irradiance = vec3<f32>(0.0);
+    var count = 0.0;
+    for (var phi = 0.0; phi < 2.0 * PI; phi += delta) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta += delta) {
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let sample = textureSampleLevel(t_source, s_source, basis * local, bake.source_mip).rgb;
+            irradiance += sample * cos(theta) * sin(theta);
+            count += 1.0;
+        }
+    }
+    return vec4<f32>(PI * irradiance / count, 1.0);
+}
+
+fn radical_inverse_vdc(bits_in: u32) -> f32 {
+    var bits = (bits_in << 16u) | (bits_in >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return f32(bits) * 2.3283064365386963e-10;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), radical_inverse_vdc(i));
+}
+
+fn importance_sample_ggx(xi: vec2<f32>, n: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let alpha = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+    return normalize(tangent_basis(n) * h);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let alpha = roughness * roughness;
+    let alpha2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+const PREFILTER_SAMPLES: u32 = 256u;
+
+// GGX prefiltered radiance for one roughness level, assuming n = v = r.
+// Samples read lower environment mips by pdf to avoid fireflies.
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n = cube_direction(bake.face, in.uv);
+    let v = n;
+    if (bake.roughness <= 0.0) {
+        return vec4<f32>(textureSampleLevel(t_source, s_source, n, 0.0).rgb, 1.0);
+    }
+    let texel_solid_angle = 4.0 * PI / (6.0 * bake.source_size * bake.source_size);
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < PREFILTER_SAMPLES; i += 1u) {
+        let h = importance_sample_ggx(hammersley(i, PREFILTER_SAMPLES), n, bake.roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = dot(n, l);
+        if (n_dot_l > 0.0) {
+            let n_dot_h = max(dot(n, h), 0.0);
+            let pdf = distribution_ggx(n_dot_h, bake.roughness) * 0.25 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(PREFILTER_SAMPLES) * pdf);
+            let mip = max(0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0, 0.0);
+            color += textureSampleLevel(t_source, s_source, l, mip).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return vec4<f32>(color / max(weight, 0.0001), 1.0);
+}
+
+fn visibility_smith_ggx(n_dot_l: f32, n_dot_v: f32, roughness: f32) -> f32 {
+    let alpha = roughness * roughness;
+    let alpha2 = alpha * alpha;
+    let ggx_v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - alpha2) + alpha2);
+    let ggx_l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - alpha2) + alpha2);
+    return 0.5 / max(ggx_v + ggx_l, 0.0001);
+}
+
+const BRDF_SAMPLES: u32 = 512u;
+
+// Split sum scale (r) and bias (g) applied to F0, indexed by N.V and roughness
+@fragment
+fn fs_brdf_lut(in: VertexOutput) -> @location(0) vec4<f32> {
+    let uv = in.uv * vec2<f32>(0.5, -0.5) + 0.5;
+    let n_dot_v = max(uv.x, 0.001);
+    let roughness = uv.y;
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let n = vec3<f32>(0.0, 0.0, 1.0);
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < BRDF_SAMPLES; i += 1u) {
+        let h = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), n, roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(v, h), 0.0);
+        if (n_dot_l > 0.0) {
+            let visibility = visibility_smith_ggx(n_dot_l, n_dot_v, roughness);
+            let weight = 4.0 * visibility * n_dot_l * v_dot_h / max(n_dot_h, 0.0001);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fresnel) * weight;
+            bias += fresnel * weight;
+        }
+    }
+    return vec4<f32>(scale / f32(BRDF_SAMPLES), bias / f32(BRDF_SAMPLES), 0.0, 1.0);
+}
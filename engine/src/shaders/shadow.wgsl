@@ -0,0 +1,98 @@
+// Depth only pass rendering the scene from a shadow casting light
+
+struct ShadowCaster {
+    view_proj: mat4x4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> caster: ShadowCaster;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    return caster.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+}
+
+// MASK materials discard texels below the alpha cutoff, like the main pass
+
+struct TextureTransform {
+    matrix: mat3x3<f32>,
+    tex_coord: u32,
+};
+
+// Leading fields of `MaterialUniform` in texture.wgsl
+struct MaterialUniform {
+    base_color_factor: vec4<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    has_normal_texture: u32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+    occlusion_strength: f32,
+    emissive_factor: vec3<f32>,
+    emissive_strength: f32,
+    base_color_transform: TextureTransform,
+};
+
+@group(1) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(1) @binding(1)
+var s_diffuse: sampler;
+@group(1) @binding(2)
+var<uniform> material: MaterialUniform;
+
+struct MaskedVertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) tex_coords: vec2<f32>,
+    @location(12) tex_coords_1: vec2<f32>,
+}
+
+struct MaskedVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) tex_coords_1: vec2<f32>,
+    @location(2) alpha: f32,
+}
+
+@vertex
+fn vs_masked(model: MaskedVertexInput, instance: InstanceInput) -> MaskedVertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: MaskedVertexOutput;
+    out.clip_position = caster.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    out.tex_coords_1 = model.tex_coords_1;
+    out.alpha = model.color.a;
+    return out;
+}
+
+@fragment
+fn fs_masked(in: MaskedVertexOutput) {
+    let transform = material.base_color_transform;
+    let uv = select(in.tex_coords, in.tex_coords_1, transform.tex_coord == 1u);
+    let texel = textureSample(t_diffuse, s_diffuse, (transform.matrix * vec3<f32>(uv, 1.0)).xy);
+    if (texel.a * material.base_color_factor.a * in.alpha < material.alpha_cutoff) {
+        discard;
+    }
+}
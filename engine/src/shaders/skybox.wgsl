@@ -0,0 +1,90 @@
+// Background behind the opaque geometry. Drawn at the far plane with a
+// LessEqual depth test, so only pixels nothing was drawn to are touched.
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct EnvironmentUniform {
+    intensity: f32,
+    prefiltered_mip_count: f32,
+};
+@group(1) @binding(1)
+var t_prefiltered: texture_cube<f32>;
+@group(1) @binding(3)
+var s_environment: sampler;
+@group(1) @binding(4)
+var<uniform> environment: EnvironmentUniform;
+
+const BACKGROUND_GRADIENT: u32 = 1u;
+const BACKGROUND_ENVIRONMENT: u32 = 2u;
+
+struct BackgroundUniform {
+    top: vec4<f32>,
+    bottom: vec4<f32>,
+    mode: u32,
+    blur: f32,
+};
+@group(2) @binding(0)
+var<uniform> background: BackgroundUniform;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let x = f32((vertex_index << 1u) & 2u) * 2.0 - 1.0;
+    let y = f32(vertex_index & 2u) * 2.0 - 1.0;
+    var out: VertexOutput;
+    out.ndc = vec2<f32>(x, y);
+    out.clip_position = vec4<f32>(x, y, 1.0, 1.0);
+    return out;
+}
+
+// Same curve as texture.wgsl, so the environment matches its reflections
+fn tone_map(color_in: vec3<f32>) -> vec3<f32> {
+    let start_compression = 0.8 - 0.04;
+    let desaturation = 0.15;
+
+    var color = color_in;
+    let x = min(color.r, min(color.g, color.b));
+    var offset = 0.04;
+    if (x < 0.08) {
+        offset = x - 6.25 * x * x;
+    }
+    color -= offset;
+
+    let peak = max(color.r, max(color.g, color.b));
+    if (peak < start_compression) {
+        return color;
+    }
+
+    let d = 1.0 - start_compression;
+    let new_peak = 1.0 - d * d / (peak + d - start_compression);
+    color *= new_peak / peak;
+
+    let g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    return mix(color, vec3<f32>(new_peak), g);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    if (background.mode == BACKGROUND_ENVIRONMENT) {
+        // Two depths inside the visible range give the view ray, without
+        // relying on where the projection puts the far plane
+        let near = camera.inv_view_proj * vec4<f32>(in.ndc, 0.0, 1.0);
+        let far = camera.inv_view_proj * vec4<f32>(in.ndc, 0.25, 1.0);
+        let dir = normalize(far.xyz / far.w - near.xyz / near.w);
+        let lod = background.blur * (environment.prefiltered_mip_count - 1.0);
+        let color = textureSampleLevel(t_prefiltered, s_environment, dir, lod).rgb;
+        return vec4<f32>(tone_map(color * environment.intensity), 1.0);
+    }
+    // Gradient colors are already linear, they skip the tone mapping
+    return mix(background.bottom, background.top, in.ndc.y * 0.5 + 0.5);
+}
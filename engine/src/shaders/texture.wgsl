@@ -2,15 +2,18 @@
 struct CameraUniform {
     view_pos: vec4<f32>,
     view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
 };
 @group(1) @binding(0) // 1.
 var<uniform> camera: CameraUniform;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
-    @location(1) color: vec3<f32>,
+    @location(1) color: vec4<f32>,
     @location(2) tex_coords: vec2<f32>,
     @location(3) normal: vec3<f32>,
+    @location(4) tangent: vec4<f32>,
+    @location(12) tex_coords_1: vec2<f32>,
 }
 
 struct InstanceInput {
@@ -26,10 +29,12 @@ struct InstanceInput {
  
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
-    @location(0) color: vec3<f32>,
+    @location(0) color: vec4<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) world_normal: vec3<f32>,
     @location(3) world_position: vec3<f32>,
+    @location(4) world_tangent: vec4<f32>,
+    @location(5) tex_coords_1: vec2<f32>,
 }
 
 @vertex
@@ -50,8 +55,13 @@ fn vs_main(
         instance.normal_matrix_2,
     );
     var out: VertexOutput;
+    out.color = model.color;
     out.tex_coords = model.tex_coords;
+    out.tex_coords_1 = model.tex_coords_1;
     out.world_normal = normal_matrix * model.normal;
+    // A mirrored transform flips cross(normal, tangent), so the bitangent sign flips with it
+    let handedness = model.tangent.w * sign(determinant(normal_matrix));
+    out.world_tangent = vec4<f32>((model_matrix * vec4<f32>(model.tangent.xyz, 0.0)).xyz, handedness);
     var world_position: vec4<f32> = model_matrix * vec4<f32>(model.position, 1.0);
     out.world_position = world_position.xyz;
     out.clip_position = camera.view_proj * world_position;
@@ -63,10 +73,363 @@ var t_diffuse: texture_2d<f32>;
 @group(0)@binding(1)
 var s_diffuse: sampler;
 
+// UV set and KHR_texture_transform matrix of a texture slot
+struct TextureTransform {
+    matrix: mat3x3<f32>,
+    tex_coord: u32,
+};
+
+// Material
+struct MaterialUniform {
+    base_color_factor: vec4<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    has_normal_texture: u32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+    occlusion_strength: f32,
+    emissive_factor: vec3<f32>,
+    emissive_strength: f32,
+    base_color_transform: TextureTransform,
+    metallic_roughness_transform: TextureTransform,
+    normal_transform: TextureTransform,
+    occlusion_transform: TextureTransform,
+    emissive_transform: TextureTransform,
+};
+
+const ALPHA_OPAQUE: u32 = 0u;
+const ALPHA_MASK: u32 = 1u;
+@group(0) @binding(2)
+var<uniform> material: MaterialUniform;
+@group(0) @binding(3)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(4)
+var s_metallic_roughness: sampler;
+@group(0) @binding(5)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(6)
+var s_normal: sampler;
+@group(0) @binding(7)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(8)
+var s_emissive: sampler;
+@group(0) @binding(9)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(10)
+var s_occlusion: sampler;
+
+// Lights
+const MAX_LIGHTS: u32 = 16u;
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    inner_cone_cos: f32,
+    outer_cone_cos: f32,
+    shadow_layer: i32,
+    shadow_count: u32,
+};
+struct LightUniform {
+    lights: array<Light, MAX_LIGHTS>,
+    count: u32,
+};
+@group(2) @binding(0)
+var<uniform> light_data: LightUniform;
+
+// Shadows, one layer per spot light or directional cascade
+const MAX_SHADOW_MAPS: u32 = 8u;
+
+struct ShadowView {
+    view_proj: mat4x4<f32>,
+    split_depth: f32,
+    bias: f32,
+    normal_bias: f32,
+    uv_scale: f32,
+};
+struct ShadowUniform {
+    views: array<ShadowView, MAX_SHADOW_MAPS>,
+    camera_forward: vec4<f32>,
+};
+@group(2) @binding(1)
+var t_shadow: texture_depth_2d_array;
+@group(2) @binding(2)
+var s_shadow: sampler_comparison;
+@group(2) @binding(3)
+var<uniform> shadow_data: ShadowUniform;
+
+// Image based lighting, baked by environment.wgsl
+struct EnvironmentUniform {
+    intensity: f32,
+    prefiltered_mip_count: f32,
+};
+@group(3) @binding(0)
+var t_irradiance: texture_cube<f32>;
+@group(3) @binding(1)
+var t_prefiltered: texture_cube<f32>;
+@group(3) @binding(2)
+var t_brdf_lut: texture_2d<f32>;
+@group(3) @binding(3)
+var s_environment: sampler;
+@group(3) @binding(4)
+var<uniform> environment: EnvironmentUniform;
+
+const PI: f32 = 3.14159265359;
+
+// Fraction of the light reaching `world_position`, 3x3 PCF
+fn shadow_factor(light: Light, world_position: vec3<f32>, n: vec3<f32>, l: vec3<f32>) -> f32 {
+    if (light.shadow_layer < 0) {
+        return 1.0;
+    }
+    var layer = u32(light.shadow_layer);
+    // Biases of spot lights grow with the distance, like their texels
+    var bias_scale = 1.0;
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        let depth = dot(world_position - camera.view_pos.xyz, shadow_data.camera_forward.xyz);
+        var cascade = 0u;
+        while (cascade < light.shadow_count && depth > shadow_data.views[layer + cascade].split_depth) {
+            cascade += 1u;
+        }
+        if (cascade == light.shadow_count) {
+            return 1.0;
+        }
+        layer += cascade;
+    } else {
+        bias_scale = distance(light.position, world_position);
+    }
+
+    let view = shadow_data.views[layer];
+    let offset = n * view.normal_bias + l * view.bias;
+    let clip = view.view_proj * vec4<f32>(world_position + offset * bias_scale, 1.0);
+    if (clip.w <= 0.0) {
+        return 1.0;
+    }
+    let ndc = clip.xyz / clip.w;
+    if (abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0 || ndc.z > 1.0) {
+        return 1.0;
+    }
+    // Layers only use the top left `uv_scale` of the texture
+    let uv = (ndc.xy * vec2<f32>(0.5, -0.5) + 0.5) * view.uv_scale;
+    let texel = 1.0 / f32(textureDimensions(t_shadow).x);
+    let uv_min = vec2<f32>(0.5 * texel);
+    let uv_max = vec2<f32>(view.uv_scale - 0.5 * texel);
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let sample_uv = clamp(uv + vec2<f32>(f32(x), f32(y)) * texel, uv_min, uv_max);
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, sample_uv, layer, ndc.z);
+        }
+    }
+    return lit / 9.0;
+}
+
+// Range falloff recommended by KHR_lights_punctual
+fn range_attenuation(range: f32, distance: f32) -> f32 {
+    if (range <= 0.0) {
+        return 1.0 / max(distance * distance, 0.0001);
+    }
+    let ratio = distance / range;
+    return clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0) / max(distance * distance, 0.0001);
+}
+
+fn spot_attenuation(light: Light, l: vec3<f32>) -> f32 {
+    let cd = dot(light.direction, -l);
+    let t = clamp((cd - light.outer_cone_cos) / max(light.inner_cone_cos - light.outer_cone_cos, 0.0001), 0.0, 1.0);
+    return t * t;
+}
+
+// Trowbridge-Reitz GGX normal distribution
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+// Height-correlated Smith visibility, G / (4 * N.L * N.V) folded in
+fn visibility_smith_ggx(n_dot_l: f32, n_dot_v: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let ggx_v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - alpha2) + alpha2);
+    let ggx_l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - alpha2) + alpha2);
+    let ggx = ggx_v + ggx_l;
+    if (ggx > 0.0) {
+        return 0.5 / ggx;
+    }
+    return 0.0;
+}
+
+fn fresnel_schlick(f0: vec3<f32>, v_dot_h: f32) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(clamp(1.0 - v_dot_h, 0.0, 1.0), 5.0);
+}
+
+// Split sum approximation of the environment lighting
+fn ibl(n: vec3<f32>, v: vec3<f32>, base_color: vec3<f32>, metallic: f32, roughness: f32) -> vec3<f32> {
+    let n_dot_v = clamp(dot(n, v), 0.0, 1.0);
+    let r = reflect(-v, n);
+    let c_diff = mix(base_color, vec3<f32>(0.0), metallic);
+    let f0 = mix(vec3<f32>(0.04), base_color, metallic);
+
+    // Fresnel with a roughness term keeps rough edges from over brightening
+    let f_r = max(vec3<f32>(1.0 - roughness), f0) - f0;
+    let k_s = f0 + f_r * pow(1.0 - n_dot_v, 5.0);
+    let lut = textureSample(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness)).rg;
+    let lod = roughness * (environment.prefiltered_mip_count - 1.0);
+    let specular = textureSampleLevel(t_prefiltered, s_environment, r, lod).rgb * (k_s * lut.x + lut.y);
+    let diffuse = textureSample(t_irradiance, s_environment, n).rgb * c_diff;
+    return (diffuse + specular) * environment.intensity;
+}
+
+// Cook-Torrance metallic-roughness BRDF from the glTF 2.0 specification, appendix B
+fn brdf(
+    n: vec3<f32>,
+    v: vec3<f32>,
+    l: vec3<f32>,
+    base_color: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+) -> vec3<f32> {
+    let h = normalize(l + v);
+    let n_dot_l = clamp(dot(n, l), 0.0, 1.0);
+    let n_dot_v = clamp(abs(dot(n, v)), 0.001, 1.0);
+    let n_dot_h = clamp(dot(n, h), 0.0, 1.0);
+    let v_dot_h = clamp(dot(v, h), 0.0, 1.0);
+
+    let alpha = roughness * roughness;
+    let f0 = mix(vec3<f32>(0.04), base_color, metallic);
+    let c_diff = mix(base_color, vec3<f32>(0.0), metallic);
+
+    let f = fresnel_schlick(f0, v_dot_h);
+    let f_diffuse = (vec3<f32>(1.0) - f) * c_diff / PI;
+    let f_specular = f * distribution_ggx(n_dot_h, alpha) * visibility_smith_ggx(n_dot_l, n_dot_v, alpha);
+    return (f_diffuse + f_specular) * n_dot_l;
+}
+
+// Khronos PBR Neutral tone mapper, the sample viewer default
+fn tone_map(color_in: vec3<f32>) -> vec3<f32> {
+    let start_compression = 0.8 - 0.04;
+    let desaturation = 0.15;
+
+    var color = color_in;
+    let x = min(color.r, min(color.g, color.b));
+    var offset = 0.04;
+    if (x < 0.08) {
+        offset = x - 6.25 * x * x;
+    }
+    color -= offset;
+
+    let peak = max(color.r, max(color.g, color.b));
+    if (peak < start_compression) {
+        return color;
+    }
+
+    let d = 1.0 - start_compression;
+    let new_peak = 1.0 - d * d / (peak + d - start_compression);
+    color *= new_peak / peak;
+
+    let g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    return mix(color, vec3<f32>(new_peak), g);
+}
+
+fn texture_uv(in: VertexOutput, transform: TextureTransform) -> vec2<f32> {
+    let uv = select(in.tex_coords, in.tex_coords_1, transform.tex_coord == 1u);
+    return (transform.matrix * vec3<f32>(uv, 1.0)).xy;
+}
+
+fn base_color(in: VertexOutput) -> vec4<f32> {
+    // The base color texture is sRGB, sampling returns linear values. COLOR_0
+    // multiplies it like the factor does
+    return textureSample(t_diffuse, s_diffuse, texture_uv(in, material.base_color_transform)) * material.base_color_factor * in.color;
+}
+
+// Output alpha for the alpha mode, masked fragments below the cutoff are discarded
+fn output_alpha(alpha: f32) -> f32 {
+    if (material.alpha_mode == ALPHA_MASK) {
+        if (alpha < material.alpha_cutoff) {
+            discard;
+        }
+        return 1.0;
+    } else if (material.alpha_mode == ALPHA_OPAQUE) {
+        return 1.0;
+    }
+    return alpha;
+}
+
+fn emission(in: VertexOutput) -> vec3<f32> {
+    let emissive = textureSample(t_emissive, s_emissive, texture_uv(in, material.emissive_transform)).rgb;
+    return emissive * material.emissive_factor * material.emissive_strength;
+}
+
+@fragment
+fn fs_main(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> @location(0) vec4<f32> {
+    let base_color = base_color(in);
+    let alpha = output_alpha(base_color.a);
+    let metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, texture_uv(in, material.metallic_roughness_transform));
+    let metallic = clamp(material.metallic_factor * metallic_roughness.b, 0.0, 1.0);
+    let roughness = clamp(material.roughness_factor * metallic_roughness.g, 0.04, 1.0);
+
+    let v = normalize(camera.view_pos.xyz - in.world_position);
+
+    // Meshes without normals fall back to the face normal, turned towards the viewer
+    var face_normal = normalize(cross(dpdx(in.world_position), dpdy(in.world_position)));
+    if (dot(face_normal, v) < 0.0) {
+        face_normal = -face_normal;
+    }
+    // Back faces are only drawn for double sided materials, they shade with
+    // the whole tangent frame flipped
+    let facing = select(-1.0, 1.0, front_facing);
+    var n = face_normal;
+    if (length(in.world_normal) > 0.0) {
+        n = normalize(in.world_normal) * facing;
+    }
+
+    let geometric_normal = n;
+
+    // Normal texture in tangent space, bitangent = cross(normal, tangent) * w as in glTF
+    let tangent_normal = textureSample(t_normal, s_normal, texture_uv(in, material.normal_transform)).xyz * 2.0 - 1.0;
+    if (material.has_normal_texture != 0u && length(in.world_tangent.xyz) > 0.0) {
+        let tangent = in.world_tangent.xyz * facing;
+        let t = normalize(tangent - n * dot(n, tangent));
+        let b = cross(n, t) * in.world_tangent.w * facing;
+        let scaled = normalize(tangent_normal * vec3<f32>(material.normal_scale, material.normal_scale, 1.0));
+        n = normalize(mat3x3<f32>(t, b, n) * scaled);
+    }
+    // Occlusion only darkens the environment light, punctual lights have shadows
+    let occlusion = textureSample(t_occlusion, s_occlusion, texture_uv(in, material.occlusion_transform)).r;
+    var color = ibl(n, v, base_color.rgb, metallic, roughness) * mix(1.0, occlusion, material.occlusion_strength);
+    for (var i = 0u; i < min(light_data.count, MAX_LIGHTS); i += 1u) {
+        let light = light_data.lights[i];
+        var l = -light.direction;
+        var attenuation = 1.0;
+        if (light.kind != LIGHT_DIRECTIONAL) {
+            let to_light = light.position - in.world_position;
+            l = normalize(to_light);
+            attenuation = range_attenuation(light.range, length(to_light));
+            if (light.kind == LIGHT_SPOT) {
+                attenuation *= spot_attenuation(light, l);
+            }
+        }
+        attenuation *= shadow_factor(light, in.world_position, geometric_normal, l);
+        let radiance = light.color * light.intensity * attenuation;
+        color += brdf(n, v, l, base_color.rgb, metallic, roughness) * radiance;
+    }
+
+    color += emission(in);
+
+    // The render target is sRGB, so the hardware encodes the linear result
+    return vec4<f32>(tone_map(color), alpha);
+}
 
+// Points and lines without normals have nothing to light, they show their
+// base color and emission as they are
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    var textureColour: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    return textureColour;
+fn fs_unlit(in: VertexOutput) -> @location(0) vec4<f32> {
+    let base_color = base_color(in);
+    let alpha = output_alpha(base_color.a);
+    return vec4<f32>(tone_map(base_color.rgb + emission(in)), alpha);
 }
- 
\ No newline at end of file
@@ -0,0 +1,469 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Transform, Vector3};
+
+use crate::camera::{Camera, OPENGL_TO_WGPU_MATRIX};
+use crate::geometry::Aabb;
+use crate::light::{Light, LightKind, LightUniform, MAX_LIGHTS};
+use crate::model::{AlphaMode, DrawShadow, Model};
+use crate::texture::Texture;
+use crate::utils::{InstanceRaw, Vertex};
+
+/// Layers of the shadow map array, `MAX_SHADOW_MAPS` in texture.wgsl.
+pub const MAX_SHADOW_MAPS: usize = 8;
+/// Cascades per directional light.
+pub const CASCADE_COUNT: usize = 3;
+/// Blend between uniform (0) and logarithmic (1) cascade splits.
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+/// Caster uniforms are bound with dynamic offsets, which must be aligned.
+const CASTER_STRIDE: wgpu::BufferAddress = 256;
+
+/// Per light shadow configuration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+    /// Edge length of the shadow map, for every cascade of a directional light
+    pub resolution: u32,
+    /// Offset towards the light before the depth compare, in shadow map texels
+    pub bias: f32,
+    /// Offset along the surface normal, in shadow map texels
+    pub normal_bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            bias: 0.5,
+            normal_bias: 1.5,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowViewRaw {
+    view_proj: [[f32; 4]; 4],
+    // Cascades cover view depths up to this distance
+    split_depth: f32,
+    // World space, per unit of distance to the light for spot lights
+    bias: f32,
+    normal_bias: f32,
+    // Part of the layer used, resolution / texture size
+    uv_scale: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniform {
+    views: [ShadowViewRaw; MAX_SHADOW_MAPS],
+    camera_forward: [f32; 4],
+}
+
+/// Depth maps of the shadow casting lights, rendered every frame before the
+/// main pass.
+pub struct ShadowMaps {
+    texture: Texture,
+    size: u32,
+    layer_views: Vec<wgpu::TextureView>,
+    pipeline: wgpu::RenderPipeline,
+    // Samples base color alpha for MASK materials
+    masked_pipeline: wgpu::RenderPipeline,
+    caster_buffer: wgpu::Buffer,
+    caster_bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    // Resolution of every layer rendered this frame
+    active: Vec<u32>,
+    // `update` runs every frame, running out of layers is only reported once
+    warned_out_of_maps: bool,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device, material_layout: &wgpu::BindGroupLayout) -> Self {
+        let size = ShadowSettings::default().resolution;
+        let (texture, layer_views) = Self::create_texture(device, size);
+
+        let caster_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Caster Buffer"),
+            size: CASTER_STRIDE * MAX_SHADOW_MAPS as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let caster_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(
+                        std::mem::size_of::<[[f32; 4]; 4]>() as u64
+                    ),
+                },
+                count: None,
+            }],
+            label: Some("shadow_caster_bind_group_layout"),
+        });
+        let caster_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &caster_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &caster_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<[[f32; 4]; 4]>() as u64),
+                }),
+            }],
+            label: Some("shadow_caster_bind_group"),
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Buffer"),
+            size: std::mem::size_of::<ShadowUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Shadow Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/shadow.wgsl").into()),
+        });
+        let pipeline = Self::create_pipeline(device, &module, &[&caster_layout], false);
+        let masked_pipeline =
+            Self::create_pipeline(device, &module, &[&caster_layout, material_layout], true);
+
+        Self {
+            texture,
+            size,
+            layer_views,
+            pipeline,
+            masked_pipeline,
+            caster_buffer,
+            caster_bind_group,
+            uniform_buffer,
+            active: Vec::new(),
+            warned_out_of_maps: false,
+        }
+    }
+
+    /// Depth only pipeline, the masked variant also binds the material to
+    /// discard cut out texels.
+    fn create_pipeline(
+        device: &wgpu::Device,
+        module: &wgpu::ShaderModule,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+        masked: bool,
+    ) -> wgpu::RenderPipeline {
+        let label = if masked { "Masked Shadow" } else { "Shadow" };
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&format!("{label} Pipeline Layout")),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&format!("{label} Pipeline")),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module,
+                entry_point: if masked { "vs_masked" } else { "vs_main" },
+                buffers: &[Vertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: masked.then_some(wgpu::FragmentState {
+                module,
+                entry_point: "fs_masked",
+                targets: &[],
+            }),
+            // Both faces cast, so open meshes and single planes still shadow
+            primitive: wgpu::PrimitiveState {
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_texture(device: &wgpu::Device, size: u32) -> (Texture, Vec<wgpu::TextureView>) {
+        let texture =
+            Texture::create_shadow_texture(device, size, MAX_SHADOW_MAPS as u32, "shadow_texture");
+        let layer_views = (0..MAX_SHADOW_MAPS as u32)
+            .map(|layer| {
+                texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        (texture, layer_views)
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    /// The comparison sampler the shadow maps are read with.
+    pub fn sampler(&self) -> &wgpu::Sampler {
+        self.texture
+            .sampler
+            .as_ref()
+            .expect("shadow textures own a comparison sampler")
+    }
+
+    pub fn uniform_buffer(&self) -> &wgpu::Buffer {
+        &self.uniform_buffer
+    }
+
+    /// Fits a view per cascade and spot light for this frame and records the
+    /// layers in `light_uniform`. Returns true when the depth texture was
+    /// recreated, bind groups holding it have to be rebuilt.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        lights: &[Light],
+        camera: &Camera,
+        bounds: &Aabb,
+        light_uniform: &mut LightUniform,
+    ) -> bool {
+        let mut views = Vec::new();
+        self.active.clear();
+        if !bounds.is_empty() {
+            for (index, light) in lights.iter().enumerate().take(MAX_LIGHTS) {
+                let Some(settings) = light.shadow else {
+                    continue;
+                };
+                let light_views = match light.kind {
+                    LightKind::Directional => cascade_views(light, settings, camera, bounds),
+                    LightKind::Spot { .. } => vec![spot_view(light, settings, bounds)],
+                    LightKind::Point => continue,
+                };
+                if views.len() + light_views.len() > MAX_SHADOW_MAPS {
+                    if !self.warned_out_of_maps {
+                        log::warn!("Out of shadow maps, {} casts no shadow", light.name);
+                        self.warned_out_of_maps = true;
+                    }
+                    continue;
+                }
+                light_uniform.set_shadow(index, views.len() as u32, light_views.len() as u32);
+                for view in light_views {
+                    self.active.push(settings.resolution);
+                    views.push(view);
+                }
+            }
+        }
+
+        // One texture holds every layer, sized for the largest resolution
+        let max_size = device.limits().max_texture_dimension_2d;
+        let size = self
+            .active
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(1)
+            .clamp(1, max_size);
+        let recreated = size > self.size;
+        if recreated {
+            (self.texture, self.layer_views) = Self::create_texture(device, size);
+            self.size = size;
+        }
+
+        let forward = (camera.target - camera.eye).normalize();
+        let mut uniform = ShadowUniform {
+            views: [bytemuck::Zeroable::zeroed(); MAX_SHADOW_MAPS],
+            camera_forward: forward.extend(0.0).into(),
+        };
+        for (layer, (raw, resolution)) in views.iter_mut().zip(&mut self.active).enumerate() {
+            *resolution = (*resolution).min(self.size);
+            raw.uv_scale = *resolution as f32 / self.size as f32;
+            uniform.views[layer] = *raw;
+            queue.write_buffer(
+                &self.caster_buffer,
+                CASTER_STRIDE * layer as wgpu::BufferAddress,
+                bytemuck::cast_slice(&raw.view_proj),
+            );
+        }
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        recreated
+    }
+
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, model: &Model) {
+        for (layer, resolution) in self.active.iter().enumerate() {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.layer_views[layer],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            let resolution = *resolution as f32;
+            render_pass.set_viewport(0.0, 0.0, resolution, resolution, 0.0, 1.0);
+            render_pass.set_bind_group(
+                0,
+                &self.caster_bind_group,
+                &[(CASTER_STRIDE * layer as wgpu::BufferAddress) as u32],
+            );
+            render_pass.set_vertex_buffer(1, model.instance_buffer.slice(..));
+            // Points and lines cover no area and blended surfaces let light
+            // through, so neither casts a shadow
+            let casters = model.meshes.iter().filter(|mesh| {
+                !mesh.instances.is_empty()
+                    && mesh.topology == wgpu::PrimitiveTopology::TriangleList
+                    && model.materials[mesh.material].alpha_mode != AlphaMode::Blend
+            });
+            let (masked, opaque): (Vec<_>, Vec<_>) = casters
+                .partition(|mesh| model.materials[mesh.material].alpha_mode == AlphaMode::Mask);
+            render_pass.set_pipeline(&self.pipeline);
+            for mesh in opaque {
+                render_pass.draw_mesh_shadow(mesh, mesh.instances.clone());
+            }
+            render_pass.set_pipeline(&self.masked_pipeline);
+            for mesh in masked {
+                render_pass.set_bind_group(1, &model.materials[mesh.material].bind_group, &[]);
+                render_pass.draw_mesh_shadow(mesh, mesh.instances.clone());
+            }
+        }
+    }
+}
+
+/// A stable up vector for a light looking along `direction`.
+fn light_up(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+/// Corners of the camera frustum between two view depths.
+fn frustum_corners(camera: &Camera, near: f32, far: f32) -> [Point3<f32>; 8] {
+    let forward = (camera.target - camera.eye).normalize();
+    let right = forward.cross(camera.up).normalize();
+    let up = right.cross(forward);
+    let tan = (Rad::from(cgmath::Deg(camera.fovy)).0 * 0.5).tan();
+    let mut corners = [camera.eye; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let depth = if i < 4 { near } else { far };
+        let half_height = depth * tan;
+        let half_width = half_height * camera.aspect;
+        let x = if i & 1 == 0 { -half_width } else { half_width };
+        let y = if i & 2 == 0 {
+            -half_height
+        } else {
+            half_height
+        };
+        *corner = camera.eye + forward * depth + right * x + up * y;
+    }
+    corners
+}
+
+/// Splits the camera range into cascades, each fitted with an orthographic
+/// view around the bounding sphere of its frustum slice.
+fn cascade_views(
+    light: &Light,
+    settings: ShadowSettings,
+    camera: &Camera,
+    bounds: &Aabb,
+) -> Vec<ShadowViewRaw> {
+    let direction = light.direction.normalize();
+    let up = light_up(direction);
+    // Nothing past the farthest corner of the scene needs a shadow
+    let scene_distance = bounds
+        .corners()
+        .iter()
+        .map(|corner| (corner - camera.eye).magnitude())
+        .fold(0.0, f32::max);
+    let near = camera.znear;
+    let far = camera.zfar.min(scene_distance).max(near * 2.0);
+
+    let rotation = Matrix4::look_to_rh(Point3::origin(), direction, up);
+    let inverse_rotation = rotation.invert().unwrap_or(Matrix4::identity());
+    let mut split_near = near;
+    (1..=CASCADE_COUNT)
+        .map(|cascade| {
+            let p = cascade as f32 / CASCADE_COUNT as f32;
+            let log = near * (far / near).powf(p);
+            let uniform = near + (far - near) * p;
+            let split_far = CASCADE_SPLIT_LAMBDA * log + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform;
+
+            let corners = frustum_corners(camera, split_near, split_far);
+            let center = corners.iter().fold(Point3::origin(), |sum, corner| {
+                sum + corner.to_vec() / corners.len() as f32
+            });
+            let radius = corners
+                .iter()
+                .map(|corner| (corner - center).magnitude())
+                .fold(0.0, f32::max);
+            // Rounded up so the view only changes size in steps
+            let radius = (radius * 16.0).ceil() / 16.0;
+
+            // Moving the view in whole texels keeps shadow edges from crawling
+            let texel = 2.0 * radius / settings.resolution as f32;
+            let mut light_center = rotation.transform_point(center);
+            light_center.x = (light_center.x / texel).floor() * texel;
+            light_center.y = (light_center.y / texel).floor() * texel;
+            let center = inverse_rotation.transform_point(light_center);
+
+            // The depth range reaches back to every caster in the scene
+            let view = Matrix4::look_to_rh(center, direction, up);
+            let (mut z_near, mut z_far) = (-radius, radius);
+            for corner in bounds.corners() {
+                let distance = -view.transform_point(corner).z;
+                z_near = z_near.min(distance);
+                z_far = z_far.max(distance);
+            }
+            let proj = cgmath::ortho(-radius, radius, -radius, radius, z_near, z_far);
+
+            split_near = split_far;
+            ShadowViewRaw {
+                view_proj: (OPENGL_TO_WGPU_MATRIX * proj * view).into(),
+                split_depth: split_far,
+                bias: settings.bias * texel,
+                normal_bias: settings.normal_bias * texel,
+                uv_scale: 1.0,
+            }
+        })
+        .collect()
+}
+
+/// Perspective view covering the spot cone.
+fn spot_view(light: &Light, settings: ShadowSettings, bounds: &Aabb) -> ShadowViewRaw {
+    let outer_cone_angle = match light.kind {
+        LightKind::Spot {
+            outer_cone_angle, ..
+        } => outer_cone_angle,
+        _ => std::f32::consts::FRAC_PI_4,
+    };
+    let direction = light.direction.normalize();
+    let view = Matrix4::look_to_rh(light.position, direction, light_up(direction));
+    let far = light.range.unwrap_or_else(|| {
+        bounds
+            .corners()
+            .iter()
+            .map(|corner| (corner - light.position).magnitude())
+            .fold(0.0, f32::max)
+    });
+    let far = far.max(0.1);
+    let fov = (2.0 * outer_cone_angle + 0.1).min(3.0);
+    let proj = cgmath::perspective(Rad(fov), 1.0, far * 0.005, far);
+    // Texel footprint one unit away from the light
+    let texel = 2.0 * (fov * 0.5).tan() / settings.resolution as f32;
+    ShadowViewRaw {
+        view_proj: (OPENGL_TO_WGPU_MATRIX * proj * view).into(),
+        split_depth: f32::MAX,
+        bias: settings.bias * texel,
+        normal_bias: settings.normal_bias * texel,
+        uv_scale: 1.0,
+    }
+}
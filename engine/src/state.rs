@@ -1,30 +1,35 @@
-use std::collections::HashMap;
 use std::iter;
 use wgpu::util::DeviceExt;
 use wgpu::BindGroupLayout;
 use winit::{event::*, window::Window};
 
+use crate::background::{Background, Skybox};
 use crate::camera::{Camera, CameraController, CameraUniform};
-use crate::model::{DrawModel, Model};
-use crate::pipeline::Pipeline;
+use crate::environment::Environment;
+use crate::light::{Light, LightRig, LightUniform};
+use crate::model::{AlphaMode, DrawModel, Mesh, Model};
+use crate::pipeline::{PipelineCache, PipelineKey, VertexLayoutKey};
 use crate::texture::{self, Texture};
-use crate::utils::{Instance, InstanceRaw, Vertex};
+use crate::utils::{InstanceRaw, Vertex};
 use cgmath::prelude::*;
 
 use crate::resources;
+use crate::shadow::ShadowMaps;
 
 pub struct State {
     #[allow(dead_code)]
     instance: wgpu::Instance,
-    #[allow(dead_code)]
     adapter: wgpu::Adapter,
     surface: wgpu::Surface,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
-    clear_color: wgpu::Color,
     window: Window,
+
+    // Background
+    background: Background,
+    skybox: Skybox,
     
     // Camera
     camera: Camera,
@@ -37,9 +42,23 @@ pub struct State {
     // Depth Buffer
     depth_texture: Texture,
 
-    // instance
-    instances: Vec<Instance>,
-    instance_buffer: wgpu::Buffer,
+    // Lights
+    lights: Vec<Light>,
+    light_rig: LightRig,
+    light_buffer: wgpu::Buffer,
+    light_bind_group: wgpu::BindGroup,
+    light_bind_group_layout: wgpu::BindGroupLayout,
+    shadow_maps: ShadowMaps,
+
+    // Image based lighting
+    environment: Environment,
+    environment_bind_group_layout: wgpu::BindGroupLayout,
+
+    // Pipelines
+    pipeline_cache: PipelineCache,
+    // One key per model mesh, refreshed by `prepare_pipelines`
+    pipeline_keys: Vec<PipelineKey>,
+    polygon_mode: wgpu::PolygonMode,
 
     // Model
     model: Model,
@@ -72,7 +91,11 @@ impl State {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    features: wgpu::Features::empty(),
+                    // Whatever block compression the adapter has, for KTX2 textures
+                    features: adapter.features()
+                        & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                            | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+                            | wgpu::Features::TEXTURE_COMPRESSION_ASTC),
                     limits: wgpu::Limits::default(),
                 },
                 None,
@@ -101,47 +124,6 @@ impl State {
 
         surface.configure(&device, &config);
 
-        // Instance
-        const SPACE_BETWEEN: f32 = 3.0;
-        const NUM_INSTANCES_PER_ROW: u32 = 1;
-        const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(
-            NUM_INSTANCES_PER_ROW as f32 * 0.5,
-            0.0,
-            NUM_INSTANCES_PER_ROW as f32 * 0.5,
-        );
-        let instances = (0..NUM_INSTANCES_PER_ROW)
-            .flat_map(|z| {
-                (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                    let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                    let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-
-                    let position = cgmath::Vector3 {
-                        x: 0.0,
-                        y: 0.0,
-                        z: 0.0,
-                    };
-
-                    let rotation = if position.is_zero() {
-                        cgmath::Quaternion::from_axis_angle(
-                            cgmath::Vector3::unit_z(),
-                            cgmath::Deg(0.0),
-                        )
-                    } else {
-                        cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-                    };
-
-                    Instance { position, rotation }
-                })
-            })
-            .collect::<Vec<_>>();
-
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-
         // Camera
         let camera = Camera::new(
             (0.0, 1.0, 2.0).into(),
@@ -195,24 +177,63 @@ impl State {
         let depth_texture =
             texture::Texture::create_depth_texture(&device, &config, "depth_texture");
 
-        let clear_color = wgpu::Color::BLACK;
-
         // Model Loader
 
         let model = resources::load_model(url, &device, &queue)
             .await
             .unwrap();
 
-        Self {
+        // Lights, the default rig stands in when the file has none
+        let light_rig = LightRig::default();
+        let lights = if model.lights.is_empty() {
+            light_rig.lights()
+        } else {
+            model.lights.clone()
+        };
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&[LightUniform::new(&lights)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let light_bind_group_layout = LightUniform::create_bind_group_layout(&device);
+        let shadow_maps = ShadowMaps::new(&device, &model.material_layout);
+        let light_bind_group = Self::create_light_bind_group(
+            &device,
+            &light_bind_group_layout,
+            &light_buffer,
+            &shadow_maps,
+        );
+
+        let environment_bind_group_layout = Environment::create_bind_group_layout(&device);
+        let environment = Environment::studio(&device, &queue, &environment_bind_group_layout);
+
+        let background = Background::default();
+        let skybox = Skybox::new(
+            &device,
+            config.format,
+            &camera_bind_group_layout,
+            &environment_bind_group_layout,
+        );
+        skybox.update(&queue, &background);
+
+        let pipeline_cache = PipelineCache::new(
+            &device,
+            "Shader",
+            include_str!("shaders/texture.wgsl"),
+            config.format,
+        );
+
+        let mut state = Self {
             instance,
             adapter,
             surface,
             device,
             queue,
             config,
-            clear_color,
             size,
             window,
+            background,
+            skybox,
             camera,
             camera_uniform,
             camera_buffer,
@@ -220,10 +241,220 @@ impl State {
             camera_bind_group_layout,
             camera_controller,
             depth_texture,
-            instances,
-            instance_buffer,
+            lights,
+            light_rig,
+            light_buffer,
+            light_bind_group,
+            light_bind_group_layout,
+            shadow_maps,
+            environment,
+            environment_bind_group_layout,
+            pipeline_cache,
+            pipeline_keys: Vec::new(),
+            polygon_mode: wgpu::PolygonMode::Fill,
             model,
+        };
+        state.prepare_pipelines();
+        state
+    }
+
+    fn create_light_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        light_buffer: &wgpu::Buffer,
+        shadow_maps: &ShadowMaps,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&shadow_maps.texture().view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(shadow_maps.sampler()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: shadow_maps.uniform_buffer().as_entire_binding(),
+                },
+            ],
+            label: Some("light_bind_group"),
+        })
+    }
+
+    fn pipeline_key(&self, mesh: &Mesh) -> PipelineKey {
+        let material = &self.model.materials[mesh.material];
+        let blended = material.alpha_mode == AlphaMode::Blend;
+        PipelineKey {
+            material_layout: material.texture_bind_group_layout.global_id(),
+            vertex_layouts: [Vertex::desc(), InstanceRaw::desc()]
+                .iter()
+                .map(VertexLayoutKey::from)
+                .collect(),
+            topology: mesh.topology,
+            front_face: if mesh.mirrored {
+                wgpu::FrontFace::Cw
+            } else {
+                wgpu::FrontFace::Ccw
+            },
+            polygon_mode: self.polygon_mode,
+            blend: Some(if blended {
+                wgpu::BlendState::ALPHA_BLENDING
+            } else {
+                wgpu::BlendState::REPLACE
+            }),
+            cull_mode: if material.double_sided {
+                None
+            } else {
+                Some(wgpu::Face::Back)
+            },
+            depth_write: !blended,
+            unlit: mesh.unlit,
+        }
+    }
+
+    /// Instances of blended meshes as (mesh, instance) pairs, farthest from
+    /// the camera first so they composite back to front.
+    fn sorted_blend_draws(&self) -> Vec<(usize, u32)> {
+        let forward = (self.camera.target - self.camera.eye).normalize();
+        let mut draws = Vec::new();
+        for (mesh_index, mesh) in self.model.meshes.iter().enumerate() {
+            if self.model.materials[mesh.material].alpha_mode != AlphaMode::Blend {
+                continue;
+            }
+            for instance in mesh.instances.clone() {
+                let transform = &self.model.instances[instance as usize];
+                let center = transform.transform_point(mesh.bounds.center());
+                let depth = (center - self.camera.eye).dot(forward);
+                draws.push((depth, mesh_index, instance));
+            }
+        }
+        draws.sort_by(|a, b| b.0.total_cmp(&a.0));
+        draws
+            .into_iter()
+            .map(|(_, mesh_index, instance)| (mesh_index, instance))
+            .collect()
+    }
+
+    /// Makes sure every mesh of the model has a pipeline. Only keys that are
+    /// not cached yet cost a pipeline build.
+    fn prepare_pipelines(&mut self) {
+        self.pipeline_cache.set_format(self.config.format);
+        self.pipeline_keys = self
+            .model
+            .meshes
+            .iter()
+            .map(|mesh| self.pipeline_key(mesh))
+            .collect();
+        for (mesh, key) in self.model.meshes.iter().zip(&self.pipeline_keys) {
+            self.pipeline_cache.prepare(
+                &mesh.name,
+                &self.device,
+                key,
+                &self.config,
+                &[
+                    &self.model.materials[mesh.material].texture_bind_group_layout,
+                    &self.camera_bind_group_layout,
+                    &self.light_bind_group_layout,
+                    &self.environment_bind_group_layout,
+                ],
+                &[Vertex::desc(), InstanceRaw::desc()],
+            );
+        }
+    }
+
+    pub fn set_polygon_mode(&mut self, polygon_mode: wgpu::PolygonMode) {
+        self.polygon_mode = polygon_mode;
+        self.prepare_pipelines();
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    /// Lights can be edited freely, they are uploaded on the next `update`.
+    pub fn lights_mut(&mut self) -> &mut Vec<Light> {
+        &mut self.lights
+    }
+
+    pub fn light_rig(&self) -> &LightRig {
+        &self.light_rig
+    }
+
+    /// Changes the default rig. It only replaces the lights when the model
+    /// doesn't bring its own.
+    pub fn set_light_rig(&mut self, light_rig: LightRig) {
+        if self.model.lights.is_empty() {
+            self.lights = light_rig.lights();
         }
+        self.light_rig = light_rig;
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    pub fn environment_bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.environment_bind_group_layout
+    }
+
+    /// Swaps the image based lighting, the environment has to be built with
+    /// `environment_bind_group_layout`.
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = environment;
+    }
+
+    /// Loads an equirectangular `.hdr` or `.exr` image from `url` and makes it
+    /// the current environment.
+    pub async fn load_environment(&mut self, url: &str) -> anyhow::Result<()> {
+        let (path, file_name) = url.rsplit_once('/').unwrap_or(("", url));
+        let environment = resources::load_environment(
+            path,
+            file_name,
+            &self.device,
+            &self.queue,
+            &self.environment_bind_group_layout,
+        )
+        .await?;
+        self.set_environment(environment);
+        Ok(())
+    }
+
+    pub fn background(&self) -> &Background {
+        &self.background
+    }
+
+    /// A transparent background also switches the surface to an alpha mode
+    /// that composites, when the platform offers one.
+    pub fn set_background(&mut self, background: Background) {
+        let alpha_modes = self.surface.get_capabilities(&self.adapter).alpha_modes;
+        let alpha_mode = if background.is_transparent() {
+            alpha_modes
+                .iter()
+                .copied()
+                .find(|mode| {
+                    matches!(
+                        mode,
+                        wgpu::CompositeAlphaMode::PreMultiplied
+                            | wgpu::CompositeAlphaMode::PostMultiplied
+                    )
+                })
+                .unwrap_or(alpha_modes[0])
+        } else {
+            alpha_modes[0]
+        };
+        if alpha_mode != self.config.alpha_mode {
+            self.config.alpha_mode = alpha_mode;
+            self.surface.configure(&self.device, &self.config);
+        }
+        self.skybox.update(&self.queue, &background);
+        self.background = background;
     }
 
     pub fn window(&self) -> &Window {
@@ -238,6 +469,7 @@ impl State {
             self.surface.configure(&self.device, &self.config);
             self.depth_texture =
                 texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+            self.prepare_pipelines();
         }
     }
     #[allow(unused_variables)]
@@ -267,6 +499,28 @@ impl State {
             0,
             bytemuck::cast_slice(&[self.camera_uniform]),
         );
+        let mut light_uniform = LightUniform::new(&self.lights);
+        let shadow_texture_changed = self.shadow_maps.update(
+            &self.device,
+            &self.queue,
+            &self.lights,
+            &self.camera,
+            &self.model.bounds,
+            &mut light_uniform,
+        );
+        if shadow_texture_changed {
+            self.light_bind_group = Self::create_light_bind_group(
+                &self.device,
+                &self.light_bind_group_layout,
+                &self.light_buffer,
+                &self.shadow_maps,
+            );
+        }
+        self.queue.write_buffer(
+            &self.light_buffer,
+            0,
+            bytemuck::cast_slice(&[light_uniform]),
+        );
     }
     pub fn size(&self) -> &winit::dpi::PhysicalSize<u32> {
         &self.size
@@ -286,23 +540,8 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
-        let mut render_pipelines = HashMap::new();
-        self.model.meshes.iter().for_each(|mesh| {
-            let render_pipeline = Pipeline::new(
-                &(mesh.name),
-                &self.device,
-                "Shader",
-                include_str!("shaders/texture.wgsl"),
-                &wgpu::PolygonMode::Fill,
-                &self.config,
-                &[
-                    &self.model.materials[mesh.material].texture_bind_group_layout,
-                    &self.camera_bind_group_layout,
-                ],
-                &[Vertex::desc(), InstanceRaw::desc()],
-            );
-            render_pipelines.insert(mesh.index, render_pipeline);
-        });
+        self.shadow_maps.render(&mut encoder, &self.model);
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -310,7 +549,7 @@ impl State {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(self.clear_color),
+                        load: wgpu::LoadOp::Clear(self.background.clear_color()),
                         store: Default::default(),
                     },
                 })],
@@ -325,22 +564,52 @@ impl State {
                 timestamp_writes: Default::default(),
                 occlusion_query_set: Default::default(),
             });
-            self.model.meshes.iter().for_each(|mesh| {
-                match render_pipelines.get(&mesh.index) {
+            render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+            render_pass.set_bind_group(3, &self.environment.bind_group, &[]);
+            render_pass.set_vertex_buffer(1, self.model.instance_buffer.slice(..));
+            // Opaque and masked meshes first, blended ones go after the skybox
+            for (mesh, key) in self.model.meshes.iter().zip(&self.pipeline_keys) {
+                let material = &self.model.materials[mesh.material];
+                if mesh.instances.is_empty() || material.alpha_mode == AlphaMode::Blend {
+                    continue;
+                }
+                match self.pipeline_cache.get(key) {
                     Some(render_pipeline) => {
                         render_pass.set_pipeline(&render_pipeline.get_pipeline());
-                        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-                        let material = &self.model.materials[mesh.material];
                         render_pass.draw_mesh_instanced(
                             &mesh,
                             &material,
-                            0..1 as u32,
+                            mesh.instances.clone(),
                             &self.camera_bind_group,
                         );
                     }
                     None => {}
                 }
-            });
+            }
+            if self.background.needs_skybox() {
+                self.skybox.draw(
+                    &mut render_pass,
+                    &self.camera_bind_group,
+                    &self.environment.bind_group,
+                );
+                // The skybox pipeline uses its own bind group layout
+                render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+                render_pass.set_bind_group(3, &self.environment.bind_group, &[]);
+            }
+            for (mesh_index, instance) in self.sorted_blend_draws() {
+                let mesh = &self.model.meshes[mesh_index];
+                if let Some(render_pipeline) =
+                    self.pipeline_cache.get(&self.pipeline_keys[mesh_index])
+                {
+                    render_pass.set_pipeline(render_pipeline.get_pipeline());
+                    render_pass.draw_mesh_instanced(
+                        mesh,
+                        &self.model.materials[mesh.material],
+                        instance..instance + 1,
+                        &self.camera_bind_group,
+                    );
+                }
+            }
         }
 
         self.queue.submit(iter::once(encoder.finish()));
@@ -1,12 +1,34 @@
+use std::collections::HashMap;
 use std::num::NonZeroU32;
+use std::sync::Arc;
 
 use anyhow::*;
 use image::{GenericImageView, Rgba, RgbaImage};
 
+/// How texel values are interpreted. Colors meant to be seen (base color,
+/// emissive) are sRGB encoded, data maps (normal, metallic-roughness,
+/// occlusion) are linear.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+impl ColorSpace {
+    pub fn format(self) -> wgpu::TextureFormat {
+        match self {
+            Self::Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+            Self::Linear => wgpu::TextureFormat::Rgba8Unorm,
+        }
+    }
+}
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
+    // Only depth textures own their sampler, materials bind one from the
+    // `SamplerCache`
+    pub sampler: Option<wgpu::Sampler>,
 }
 
 impl Texture {
@@ -15,36 +37,26 @@ impl Texture {
         queue: &wgpu::Queue,
         bytes: &[u8],
         label: &str,
+        color_space: ColorSpace,
     ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img.to_rgba8(), Some(label))
-    }
-
-    fn create_image_from_factor(factor: &[f32; 4]) -> RgbaImage {
-        let mut img = RgbaImage::new(256, 256);
-        let color = Rgba([
-            (factor[0] * 255.0) as u8,
-            (factor[1] * 255.0) as u8,
-            (factor[2] * 255.0) as u8,
-            (factor[3] * 255.0) as u8,
-        ]);
-        for x in 0..255 {
-            for y in 0..255 {
-                img.put_pixel(x, y, color);
-                img.put_pixel(y, x, color);
-            }
+        if crate::ktx::is_ktx2(bytes) {
+            return crate::ktx::texture_from_ktx2(device, queue, bytes, label, color_space);
         }
-        return img;
+        let img = image::load_from_memory(bytes)?;
+        Self::from_image(device, queue, &img.to_rgba8(), Some(label), color_space)
     }
 
+    /// A single texel of `factor`, for texture slots a material leaves empty.
     pub fn from_factor(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         factor: &[f32; 4],
         label: &str,
+        color_space: ColorSpace,
     ) -> Result<Self> {
-        let img = Self::create_image_from_factor(factor);
-        Self::from_image(device, queue, &img, Some(label))
+        let color = Rgba(factor.map(|channel| (channel * 255.0) as u8));
+        let img = RgbaImage::from_pixel(1, 1, color);
+        Self::from_image(device, queue, &img, Some(label), color_space)
     }
 
     pub fn from_image(
@@ -52,6 +64,7 @@ impl Texture {
         queue: &wgpu::Queue,
         img: &RgbaImage,
         label: Option<&str>,
+        color_space: ColorSpace,
     ) -> Result<Self> {
         let rgba = img;
         let dimensions = img.dimensions();
@@ -61,11 +74,12 @@ impl Texture {
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
-        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let format = color_space.format();
+        let mip_level_count = size.max_mips(wgpu::TextureDimension::D2);
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
@@ -73,37 +87,44 @@ impl Texture {
             view_formats: &[],
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
-            },
-            size,
-        );
+        // Full mip chain, each level downsampled on the CPU from the previous one
+        let mut level = rgba.clone();
+        for mip_level in 0..mip_level_count {
+            if mip_level > 0 {
+                level = image::imageops::resize(
+                    &level,
+                    (level.width() / 2).max(1),
+                    (level.height() / 2).max(1),
+                    image::imageops::FilterType::Triangle,
+                );
+            }
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &level,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * level.width()),
+                    rows_per_image: Some(level.height()),
+                },
+                wgpu::Extent3d {
+                    width: level.width(),
+                    height: level.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
-            address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
 
         Ok(Self {
             texture,
             view,
-            sampler,
+            sampler: None,
         })
     }
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float; // 1.
@@ -150,7 +171,118 @@ impl Texture {
         Self {
             texture,
             view,
-            sampler,
+            sampler: Some(sampler),
         }
     }
+
+    /// Depth array for shadow maps, one layer per shadow view. Sampled with a
+    /// comparison sampler, like `create_depth_texture`.
+    pub fn create_shadow_texture(
+        device: &wgpu::Device,
+        size: u32,
+        layers: u32,
+        label: &str,
+    ) -> Self {
+        let desc = wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        };
+        let texture = device.create_texture(&desc);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 100.0,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler: Some(sampler),
+        }
+    }
+}
+
+/// Hashable sampler state, textures with the same wrap and filter modes share
+/// one `wgpu::Sampler` through a [`SamplerCache`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerKey {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    // False samples the base level only
+    pub mipmaps: bool,
+}
+
+impl Default for SamplerKey {
+    /// Repeat with trilinear filtering, what glTF viewers use when a texture
+    /// has no sampler.
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            mipmaps: true,
+        }
+    }
+}
+
+impl SamplerKey {
+    pub fn descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        wgpu::SamplerDescriptor {
+            label: Some("Texture Sampler"),
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            lod_max_clamp: if self.mipmaps { 32.0 } else { 0.0 },
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SamplerCache {
+    samplers: HashMap<SamplerKey, Arc<wgpu::Sampler>>,
+}
+
+impl SamplerCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The sampler for `key`, created on first use.
+    pub fn get(&mut self, device: &wgpu::Device, key: SamplerKey) -> Arc<wgpu::Sampler> {
+        self.samplers
+            .entry(key)
+            .or_insert_with(|| Arc::new(device.create_sampler(&key.descriptor())))
+            .clone()
+    }
 }
@@ -25,6 +25,7 @@ extern "C" {
     pub fn log_many(a: &str, b: &str);
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RGBColor {
     /// Red component of the color
     pub r: i64,
@@ -94,6 +95,20 @@ impl InstanceRaw {
     }
 }
 
+impl InstanceRaw {
+    pub fn from_matrix(model: &cgmath::Matrix4<f32>) -> Self {
+        use cgmath::{Matrix, SquareMatrix};
+        // Normals need the inverse transpose so non-uniform scale keeps them perpendicular
+        let linear =
+            cgmath::Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        let normal = linear.invert().map(|m| m.transpose()).unwrap_or(linear);
+        InstanceRaw {
+            model: (*model).into(),
+            normal: normal.into(),
+        }
+    }
+}
+
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
@@ -112,9 +127,14 @@ impl Instance {
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: [f32; 3],
-    pub color: [f32; 3],
+    // COLOR_0, white when the primitive has no vertex colors
+    pub color: [f32; 4],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    // xyz is the tangent, w the bitangent sign
+    pub tangent: [f32; 4],
+    // TEXCOORD_1, for texture slots that pick the second UV set
+    pub tex_coords_1: [f32; 2],
 }
 impl Vertex {
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
@@ -130,18 +150,29 @@ impl Vertex {
                 wgpu::VertexAttribute {
                     offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
                     shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
+                    offset: std::mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x2,
                 },
                 wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    offset: std::mem::size_of::<[f32; 9]>() as wgpu::BufferAddress,
                     shader_location: 3,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                // Locations 5 to 11 are taken by `InstanceRaw`
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
             ],
         }
     }
@@ -169,12 +200,57 @@ fn color_converter(color: &i64) -> f64 {
 }
 
 pub fn is_gltf_file_url(path: &str) -> bool {
-    if path.ends_with(".gltf") {
+    if path.ends_with(".gltf") || path.ends_with(".glb") {
         return true;
     };
     return false;
 }
 
+pub fn is_data_uri(uri: &str) -> bool {
+    uri.starts_with("data:")
+}
+
+/// Decodes a `data:[<mediatype>][;base64],<data>` URI into raw bytes.
+pub fn decode_data_uri(uri: &str) -> anyhow::Result<Vec<u8>> {
+    use base64::Engine;
+
+    let (header, data) = uri
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(','))
+        .ok_or_else(|| anyhow::anyhow!("Malformed data uri"))?;
+    if header.ends_with(";base64") {
+        Ok(base64::engine::general_purpose::STANDARD.decode(data)?)
+    } else {
+        percent_decode(data)
+    }
+}
+
+/// Decodes the `%XX` escapes of a URI component.
+fn percent_decode(data: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = data.bytes();
+    let mut decoded = Vec::with_capacity(data.len());
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let escape = [bytes.next(), bytes.next()];
+        let hex = match escape {
+            [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        decoded.push(hex.ok_or_else(|| anyhow::anyhow!("Malformed percent escape in data uri"))?);
+    }
+    Ok(decoded)
+}
+
+/// Binary glTF files start with the ASCII magic `glTF`.
+pub fn is_glb(data: &[u8]) -> bool {
+    data.starts_with(b"glTF")
+}
+
 pub fn get_relative_path_from_url(path: &str) -> &str {
     if !is_gltf_file_url(&path) {
         return path;